@@ -0,0 +1,78 @@
+//! ディレクトリの読み込みで、メタデータを取得するスレッド数ごとの速さを比べる。
+//!
+//! `SCAN_BENCH_DIR` を指定すると、その下 (NFS や SMB のマウント先など) にテスト用の
+//! ディレクトリを作る。ローカルディスクではメタデータがキャッシュされるため差は小さい。
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rename_app_lib::scan::{self, ScanOptions};
+
+const DIRECTORIES: usize = 4;
+const FILES_PER_DIRECTORY: usize = 2_500;
+const ROUNDS: usize = 5;
+const THREADS: &[usize] = &[1, 2, 4, 8, 16];
+
+fn main() {
+    let base = env::var_os("SCAN_BENCH_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let root = base.join(format!("rename-app-scan-bench-{}", std::process::id()));
+    generate(&root).expect("failed to generate the test directory");
+
+    let expected = DIRECTORIES * (FILES_PER_DIRECTORY + 1);
+    println!(
+        "{} entries in {}, median of {} rounds",
+        expected,
+        root.display(),
+        ROUNDS
+    );
+    let mut serial = None;
+    for &threads in THREADS {
+        let time = measure(&root, threads, expected);
+        let serial = *serial.get_or_insert(time);
+        println!(
+            "{:>2} thread(s): {:>8.1} ms  ({:.2}x)",
+            threads,
+            time.as_secs_f64() * 1000.0,
+            serial.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+
+    if let Err(e) = fs::remove_dir_all(&root) {
+        eprintln!("failed to remove {}: {}", root.display(), e);
+    }
+}
+
+fn generate(root: &Path) -> std::io::Result<()> {
+    for dir in 0..DIRECTORIES {
+        let dir = root.join(format!("dir{:02}", dir));
+        fs::create_dir_all(&dir)?;
+        for file in 0..FILES_PER_DIRECTORY {
+            fs::write(dir.join(format!("IMG_{:05}.jpg", file)), b"")?;
+        }
+    }
+    Ok(())
+}
+
+fn measure(root: &Path, threads: usize, expected: usize) -> Duration {
+    let options = ScanOptions {
+        recursive: true,
+        kinds: vec![scan::EntryKind::File, scan::EntryKind::Directory],
+        metadata_threads: Some(threads),
+        ..ScanOptions::default()
+    };
+    let mut times: Vec<Duration> = (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let result = scan::scan_directory(root, &options).expect("scan failed");
+            let elapsed = start.elapsed();
+            assert_eq!(result.entries.len(), expected);
+            elapsed
+        })
+        .collect();
+    times.sort();
+    times[ROUNDS / 2]
+}
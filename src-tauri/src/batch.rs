@@ -0,0 +1,1022 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::casefold::{self, CaseFolding};
+use crate::error::RenameError;
+use crate::validation::{self, TargetPlatform, Violation};
+
+// 1件分のファイル移動
+#[derive(Clone, Debug)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictReason {
+    // バッチ外の既存ファイルと衝突する
+    AlreadyExists,
+    // バッチ内の別のファイルと移動先が重複する
+    DuplicateTarget,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Conflict {
+    pub index: usize,
+    pub path: PathBuf,
+    pub target: PathBuf,
+    pub reason: ConflictReason,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RenameMode {
+    // 1件でも失敗したらすべて元に戻す
+    #[default]
+    Atomic,
+    // 失敗したファイルを飛ばして、できるものだけリネームする
+    BestEffort,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RenameOptions {
+    pub mode: RenameMode,
+    // 新しい名前をどの環境の規則で検証するか
+    pub platform: TargetPlatform,
+    // 新しい名前に `2024/01/x.jpg` のような下位ディレクトリへの相対パスを許し、必要なら作成する
+    pub move_into_subfolders: bool,
+}
+
+impl RenameOptions {
+    /// 新しい名前を検証する。サブフォルダへ移動する場合はパスの各部分を検証する。
+    pub fn validate_name(&self, new_name: &str) -> Vec<Violation> {
+        if self.move_into_subfolders {
+            validation::validate_relative_path(new_name, self.platform)
+        } else {
+            validation::validate_name(new_name, self.platform)
+        }
+    }
+}
+
+// ファイルごとの結果
+#[derive(serde::Serialize, Debug)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum RenameOutcome {
+    #[serde(rename_all = "camelCase")]
+    Renamed { path: PathBuf, new_path: PathBuf },
+    #[serde(rename_all = "camelCase")]
+    Skipped { path: PathBuf, reason: String },
+    #[serde(rename_all = "camelCase")]
+    Failed { path: PathBuf, error: RenameError },
+}
+
+/// 実行中の進捗の通知先。中断の要求もここから受け取る。
+pub trait Observer {
+    // total 件中 done 件目の移動が終わった。path は移動元
+    fn progress(&self, _done: usize, _total: usize, _path: &Path) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+// 通知も中断もしない
+impl Observer for () {}
+
+// 実際に実行する fs::rename 1回分。entry は元の Move の添字
+// group が同じ手順は一時的な名前を経由するので、すべて実行するか、すべて元に戻す
+#[derive(Clone, Debug)]
+struct Step {
+    entry: usize,
+    group: usize,
+    from: PathBuf,
+    to: PathBuf,
+}
+
+/// 移動先が同じバッチ内の別ファイルに使われている場合の依存関係を解決し、実行順に並べる。
+///
+/// 移動先は重複しないので、依存関係は単純な連鎖 (`001 -> 002`, `002 -> 003`) か
+/// 循環 (`a -> b`, `b -> a`) のどちらかになる。連鎖は末尾から順に、循環は先頭を
+/// 一時的な名前へ退避してから実行する。
+///
+/// すべてのパスは実行前の状態で表されているので、ディレクトリの中身を先に、
+/// ディレクトリ自体を後にリネームする。
+///
+/// 大文字と小文字だけを変える移動など、同じエントリを指す名前への移動は
+/// 何もしないか失敗することがあるので、一時的な名前を経由する。
+fn plan(moves: &[Move], folding: &CaseFolding) -> Vec<Step> {
+    let sources: HashMap<PathBuf, usize> = moves
+        .iter()
+        .enumerate()
+        .map(|(index, mv)| (folding.key(&mv.from), index))
+        .collect();
+    // blockers[i] = j: moves[i] の移動先に moves[j] のファイルがまだ存在する
+    let blockers: Vec<Option<usize>> = moves
+        .iter()
+        .enumerate()
+        .map(|(index, mv)| {
+            sources
+                .get(&folding.key(&mv.to))
+                .copied()
+                .filter(|&blocker| blocker != index)
+        })
+        .collect();
+    let push = |steps: &mut Vec<Step>, index: usize, group: usize| {
+        let mv = &moves[index];
+        if folding.is_same_entry_rename(&mv.from, &mv.to) {
+            let temp = temp_path(&mv.from);
+            steps.push(Step {
+                entry: index,
+                group,
+                from: mv.from.clone(),
+                to: temp.clone(),
+            });
+            steps.push(Step {
+                entry: index,
+                group,
+                from: temp,
+                to: mv.to.clone(),
+            });
+        } else {
+            steps.push(Step {
+                entry: index,
+                group,
+                from: mv.from.clone(),
+                to: mv.to.clone(),
+            });
+        }
+    };
+
+    let mut order: Vec<usize> = (0..moves.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(moves[index].from.components().count()));
+
+    let mut done = vec![false; moves.len()];
+    let mut steps = Vec::with_capacity(moves.len());
+    let mut group = 0;
+    for start in order {
+        if done[start] {
+            continue;
+        }
+        if moves[start].from == moves[start].to {
+            done[start] = true;
+            continue;
+        }
+
+        let mut chain = vec![start];
+        let mut cycle = false;
+        let mut current = start;
+        while let Some(next) = blockers[current] {
+            if done[next] {
+                break;
+            }
+            if next == start {
+                cycle = true;
+                break;
+            }
+            if chain.contains(&next) {
+                break;
+            }
+            chain.push(next);
+            current = next;
+        }
+
+        if cycle {
+            let temp = temp_path(&moves[start].from);
+            steps.push(Step {
+                entry: start,
+                group,
+                from: moves[start].from.clone(),
+                to: temp.clone(),
+            });
+            for &index in chain[1..].iter().rev() {
+                push(&mut steps, index, group);
+            }
+            steps.push(Step {
+                entry: start,
+                group,
+                from: temp,
+                to: moves[start].to.clone(),
+            });
+            group += 1;
+        } else {
+            // 連鎖は1件ずつ独立して実行できる
+            for &index in chain.iter().rev() {
+                push(&mut steps, index, group);
+                group += 1;
+            }
+        }
+        for index in chain {
+            done[index] = true;
+        }
+    }
+    steps
+}
+
+// バッチ内のディレクトリの移動を反映して、実行後のパスを求める
+struct Relocator<'a> {
+    moves: &'a [Move],
+    sources: HashMap<&'a Path, usize>,
+    finals: Vec<Option<PathBuf>>,
+    visiting: Vec<bool>,
+}
+
+impl<'a> Relocator<'a> {
+    fn new(moves: &'a [Move]) -> Self {
+        Self {
+            moves,
+            sources: moves
+                .iter()
+                .enumerate()
+                .map(|(index, mv)| (mv.from.as_path(), index))
+                .collect(),
+            finals: vec![None; moves.len()],
+            visiting: vec![false; moves.len()],
+        }
+    }
+
+    // 実行前の状態で表した path が、親ディレクトリの移動によってどこへ移るか
+    fn relocate(&mut self, path: &Path) -> PathBuf {
+        for ancestor in path.ancestors().skip(1) {
+            if let Some(&index) = self.sources.get(ancestor) {
+                if let Some(base) = self.final_path(index) {
+                    let rest = path.strip_prefix(ancestor).unwrap_or(path);
+                    return base.join(rest);
+                }
+            }
+        }
+        path.to_path_buf()
+    }
+
+    fn final_path(&mut self, index: usize) -> Option<PathBuf> {
+        if let Some(path) = &self.finals[index] {
+            return Some(path.clone());
+        }
+        if self.visiting[index] {
+            return None;
+        }
+        self.visiting[index] = true;
+        let moves = self.moves;
+        let path = self.relocate(&moves[index].to);
+        self.finals[index] = Some(path.clone());
+        Some(path)
+    }
+}
+
+/// 各移動の、バッチ実行後の最終的なパス。ディレクトリごと移動したファイルは移動先のディレクトリ内になる。
+pub fn final_paths(moves: &[Move]) -> Vec<PathBuf> {
+    let mut relocator = Relocator::new(moves);
+    (0..moves.len())
+        .map(|index| {
+            relocator
+                .final_path(index)
+                .unwrap_or_else(|| moves[index].to.clone())
+        })
+        .collect()
+}
+
+/// 実行前の状態で表した `path` が、バッチ実行後にどこにあるかを求める。
+pub fn relocate(moves: &[Move], path: &Path) -> PathBuf {
+    Relocator::new(moves).relocate(path)
+}
+
+// 同じディレクトリ内で、まだ存在しない一時的な名前を作る
+fn temp_path(from: &Path) -> PathBuf {
+    let name = from
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut counter = 0u32;
+    loop {
+        let candidate = from.with_file_name(format!(
+            ".{}.{}-{}.rename-tmp",
+            name,
+            std::process::id(),
+            counter
+        ));
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+// 移動元と移動先のディレクトリが大文字と小文字を区別するか調べる
+fn case_folding(moves: &[Move]) -> CaseFolding {
+    CaseFolding::for_paths(
+        moves
+            .iter()
+            .flat_map(|mv| [mv.from.as_path(), mv.to.as_path()]),
+    )
+}
+
+/// 移動先がバッチ外の既存ファイルや、バッチ内の別の移動先と重なっていないか確認する。
+/// バッチ内で移動されるファイルの元の場所は、空くものとして扱う。
+/// 大文字と小文字を区別しないディレクトリでは、それらだけが異なる名前も重なるものとみなす。
+pub fn check_conflicts(moves: &[Move], folding: &CaseFolding) -> Vec<Conflict> {
+    let sources: HashSet<PathBuf> = moves.iter().map(|mv| folding.key(&mv.from)).collect();
+    // 他の移動のために作るディレクトリも移動先として埋まっている
+    let directories: HashSet<PathBuf> = missing_directories(moves)
+        .iter()
+        .map(|dir| folding.key(dir))
+        .collect();
+    let mut targets: HashMap<PathBuf, usize> = HashMap::new();
+    let mut conflicts = Vec::new();
+
+    for (index, mv) in moves.iter().enumerate() {
+        if mv.from == mv.to {
+            continue;
+        }
+        let target = folding.key(&mv.to);
+        let reason =
+            if targets.insert(target.clone(), index).is_some() || directories.contains(&target) {
+                Some(ConflictReason::DuplicateTarget)
+            } else if !sources.contains(&target)
+                && fs::symlink_metadata(&mv.to).is_ok()
+                // 正規化だけが異なる名前で同じエントリが見つかるのは移動先が空いている場合。
+                // ハードリンクは別の名前なので、同じ inode でも埋まっているとみなす
+                && !(casefold::same_normalized_name(&mv.from, &mv.to)
+                    && casefold::same_entry(&mv.from, &mv.to) == Some(true))
+            {
+                Some(ConflictReason::AlreadyExists)
+            } else {
+                None
+            };
+        if let Some(reason) = reason {
+            conflicts.push(Conflict {
+                index,
+                path: mv.from.clone(),
+                target: mv.to.clone(),
+                reason,
+            });
+        }
+    }
+    conflicts
+}
+
+/// 移動先の親ディレクトリのうち、まだ存在しないもの。作成する順 (浅いものから) に並べる。
+pub fn missing_directories(moves: &[Move]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+    for mv in moves {
+        let Some(parent) = mv.to.parent() else {
+            continue;
+        };
+        let mut new_dirs: Vec<&Path> = parent
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && fs::symlink_metadata(dir).is_err())
+            .collect();
+        new_dirs.reverse();
+        for dir in new_dirs {
+            if seen.insert(dir) {
+                missing.push(dir.to_path_buf());
+            }
+        }
+    }
+    missing
+}
+
+/// `create_directories` で作ったディレクトリを深いものから削除する。空でなければ残す。
+pub fn remove_directories(created: &[PathBuf]) -> Vec<RenameError> {
+    let mut errors = Vec::new();
+    for dir in created.iter().rev() {
+        debug!(dir:% = dir.display(); "Removing directory");
+        if let Err(e) = fs::remove_dir(dir) {
+            warn!(dir:% = dir.display(), error:% = e; "Failed to remove directory");
+            errors.push(RenameError::io(dir, &e));
+        }
+    }
+    errors
+}
+
+// 浅いものから順に作成し、作成できたものを created に加える
+fn create_directories(
+    dirs: &[PathBuf],
+    created: &mut Vec<PathBuf>,
+) -> Result<(), (PathBuf, std::io::Error)> {
+    for dir in dirs {
+        debug!(dir:% = dir.display(); "Creating directory");
+        fs::create_dir(dir).map_err(|e| (dir.clone(), e))?;
+        created.push(dir.clone());
+    }
+    Ok(())
+}
+
+/// すべての移動を実行する。途中で失敗した場合は、それまでに完了した移動を逆順に元へ戻す。
+/// `create_missing` が真なら存在しない移動先のディレクトリを作り、元へ戻すときに削除する。
+/// 中断を要求された場合も、それまでの移動を元に戻して `Cancelled` を返す。
+pub fn execute(
+    moves: &[Move],
+    create_missing: bool,
+    observer: &dyn Observer,
+) -> Result<Vec<RenameOutcome>, RenameError> {
+    let missing: Vec<PathBuf> = moves
+        .iter()
+        .filter(|mv| fs::symlink_metadata(&mv.from).is_err())
+        .map(|mv| mv.from.clone())
+        .collect();
+    if !missing.is_empty() {
+        return Err(RenameError::Missing { paths: missing });
+    }
+
+    let folding = case_folding(moves);
+    let conflicts = check_conflicts(moves, &folding);
+    if !conflicts.is_empty() {
+        return Err(RenameError::Conflicts { conflicts });
+    }
+
+    // 移動先のディレクトリを先に作っておく
+    let mut created = Vec::new();
+    let directories = if create_missing {
+        missing_directories(moves)
+    } else {
+        Vec::new()
+    };
+    if let Err((dir, e)) = create_directories(&directories, &mut created) {
+        let rollback_errors = remove_directories(&created);
+        let index = moves
+            .iter()
+            .position(|mv| mv.to.starts_with(&dir))
+            .unwrap_or_default();
+        return Err(RenameError::Failed {
+            index,
+            path: moves[index].from.clone(),
+            cause: Box::new(RenameError::io(&dir, &e)),
+            rolled_back: rollback_errors.is_empty(),
+            rollback_errors,
+        });
+    }
+
+    let steps = plan(moves, &folding);
+    let total = moves.iter().filter(|mv| mv.from != mv.to).count();
+    let mut done = 0;
+    for (position, step) in steps.iter().enumerate() {
+        if observer.is_cancelled() {
+            info!(done; "Rename was cancelled, rolling back");
+            let mut rollback_errors = rollback(&steps[..position]);
+            rollback_errors.extend(remove_directories(&created));
+            return Err(RenameError::Cancelled {
+                rolled_back: rollback_errors.is_empty(),
+                rollback_errors,
+            });
+        }
+        debug!(from:% = step.from.display(), to:% = step.to.display(); "Renaming");
+
+        if let Err(e) = fs::rename(&step.from, &step.to) {
+            warn!(
+                from:% = step.from.display(),
+                to:% = step.to.display(),
+                error:% = e;
+                "Failed to rename, rolling back"
+            );
+            let mut rollback_errors = rollback(&steps[..position]);
+            rollback_errors.extend(remove_directories(&created));
+            return Err(RenameError::Failed {
+                index: step.entry,
+                path: moves[step.entry].from.clone(),
+                cause: Box::new(RenameError::io(&step.from, &e)),
+                rolled_back: rollback_errors.is_empty(),
+                rollback_errors,
+            });
+        }
+        let mv = &moves[step.entry];
+        if step.to == mv.to {
+            done += 1;
+            observer.progress(done, total, &mv.from);
+        }
+    }
+
+    Ok(moves
+        .iter()
+        .zip(final_paths(moves))
+        .map(|(mv, new_path)| {
+            if mv.from == mv.to {
+                RenameOutcome::Skipped {
+                    path: mv.from.clone(),
+                    reason: "New name is unchanged".to_string(),
+                }
+            } else {
+                RenameOutcome::Renamed {
+                    path: mv.from.clone(),
+                    new_path,
+                }
+            }
+        })
+        .collect())
+}
+
+/// 失敗したファイルを飛ばしながら、できる限りの移動を実行する。結果は `moves` と同じ順に返す。
+/// 作ったディレクトリのうち、最後に空のまま残ったものは削除する。
+/// 中断を要求された場合は、それ以降のファイルを飛ばす。
+pub fn execute_best_effort(
+    moves: &[Move],
+    create_missing: bool,
+    observer: &dyn Observer,
+) -> Vec<RenameOutcome> {
+    let mut outcomes: Vec<Option<RenameOutcome>> = moves.iter().map(|_| None).collect();
+
+    let folding = case_folding(moves);
+    for conflict in check_conflicts(moves, &folding) {
+        let index = conflict.index;
+        outcomes[index] = Some(RenameOutcome::Failed {
+            path: conflict.path.clone(),
+            error: RenameError::Conflicts {
+                conflicts: vec![conflict],
+            },
+        });
+    }
+    for (index, mv) in moves.iter().enumerate() {
+        if outcomes[index].is_some() {
+            continue;
+        }
+        if mv.from == mv.to {
+            outcomes[index] = Some(RenameOutcome::Skipped {
+                path: mv.from.clone(),
+                reason: "New name is unchanged".to_string(),
+            });
+        } else if let Err(e) = fs::symlink_metadata(&mv.from) {
+            outcomes[index] = Some(RenameOutcome::Failed {
+                path: mv.from.clone(),
+                error: RenameError::io(&mv.from, &e),
+            });
+        }
+    }
+
+    // 移動先のディレクトリを作れなかったファイルは飛ばす
+    let mut created = Vec::new();
+    for (index, mv) in moves.iter().enumerate() {
+        if !create_missing || outcomes[index].is_some() {
+            continue;
+        }
+        let missing = missing_directories(std::slice::from_ref(mv));
+        if let Err((dir, e)) = create_directories(&missing, &mut created) {
+            outcomes[index] = Some(RenameOutcome::Failed {
+                path: mv.from.clone(),
+                error: RenameError::io(&dir, &e),
+            });
+        }
+    }
+
+    let pending: Vec<usize> = (0..moves.len())
+        .filter(|&index| outcomes[index].is_none())
+        .collect();
+    let pending_moves: Vec<Move> = pending.iter().map(|&index| moves[index].clone()).collect();
+
+    let mut done = 0;
+    let mut cancelled = false;
+    // 一時的な名前に退避しているファイルの数
+    let mut parked = 0usize;
+    let steps = plan(&pending_moves, &folding);
+    for (position, step) in steps.iter().enumerate() {
+        let index = pending[step.entry];
+        let mv = &moves[index];
+        if outcomes[index].is_some() {
+            continue;
+        }
+        // 一時的な名前のファイルが残らないよう、循環の途中では中断しない
+        if parked == 0 && observer.is_cancelled() {
+            info!(done; "Rename was cancelled");
+            cancelled = true;
+            break;
+        }
+        debug!(from:% = step.from.display(), to:% = step.to.display(); "Renaming");
+
+        // 依存先のリネームに失敗していると、移動先がまだ空いていないことがある
+        let result = if fs::symlink_metadata(&step.to).is_ok() {
+            Err(RenameOutcome::Skipped {
+                path: mv.from.clone(),
+                reason: format!("'{}' is still in use", step.to.display()),
+            })
+        } else {
+            fs::rename(&step.from, &step.to).map_err(|e| RenameOutcome::Failed {
+                path: mv.from.clone(),
+                error: RenameError::io(&step.from, &e),
+            })
+        };
+
+        match result {
+            Ok(()) if step.to == mv.to => {
+                outcomes[index] = Some(RenameOutcome::Renamed {
+                    path: mv.from.clone(),
+                    new_path: mv.to.clone(),
+                });
+            }
+            Ok(()) => parked += 1,
+            Err(outcome) => {
+                warn!(path:% = mv.from.display(), to:% = mv.to.display(); "Could not rename");
+                // 循環の途中で失敗した場合は、同じまとまりの移動をすべて元に戻す。
+                // 一部だけを戻すと、他のファイルが使っている名前を上書きしてしまう
+                let first = steps[..position]
+                    .iter()
+                    .rposition(|other| other.group != step.group)
+                    .map_or(0, |before| before + 1);
+                let mut errors: HashMap<usize, RenameError> = undo_steps(&steps[first..position])
+                    .into_iter()
+                    .map(|(entry, error)| (pending[entry], error))
+                    .collect();
+                let mut members: Vec<usize> = steps[first..]
+                    .iter()
+                    .take_while(|other| other.group == step.group)
+                    .map(|other| pending[other.entry])
+                    .collect();
+                members.sort_unstable();
+                members.dedup();
+                outcomes[index] = Some(outcome);
+                for member in members {
+                    let counted = member != index && outcomes[member].is_some();
+                    let path = moves[member].from.clone();
+                    if let Some(error) = errors.remove(&member) {
+                        outcomes[member] = Some(RenameOutcome::Failed { path, error });
+                    } else if member != index {
+                        outcomes[member] = Some(RenameOutcome::Skipped {
+                            path,
+                            reason: format!(
+                                "Rolled back because '{}' could not be renamed",
+                                mv.from.display()
+                            ),
+                        });
+                    }
+                    if !counted {
+                        done += 1;
+                        observer.progress(done, pending.len(), &moves[member].from);
+                    }
+                }
+                parked = 0;
+                continue;
+            }
+        }
+        if step.from != mv.from {
+            parked -= 1;
+        }
+        if outcomes[index].is_some() {
+            done += 1;
+            observer.progress(done, pending.len(), &mv.from);
+        }
+    }
+
+    // 作ったディレクトリのうち、移動に失敗して空のまま残ったものを片付ける
+    for dir in created.iter().rev() {
+        if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none()) {
+            remove_directories(std::slice::from_ref(dir));
+        }
+    }
+
+    // 成功した移動だけで、ディレクトリの移動を反映した最終的なパスを求め直す
+    let renamed: Vec<usize> = (0..moves.len())
+        .filter(|&index| matches!(outcomes[index], Some(RenameOutcome::Renamed { .. })))
+        .collect();
+    let renamed_moves: Vec<Move> = renamed.iter().map(|&index| moves[index].clone()).collect();
+    for (&index, final_path) in renamed.iter().zip(final_paths(&renamed_moves)) {
+        if let Some(RenameOutcome::Renamed { new_path, .. }) = outcomes[index].as_mut() {
+            *new_path = final_path;
+        }
+    }
+
+    outcomes
+        .into_iter()
+        .zip(moves)
+        .map(|(outcome, mv)| {
+            outcome.unwrap_or_else(|| RenameOutcome::Skipped {
+                path: mv.from.clone(),
+                reason: if cancelled {
+                    "Rename was cancelled".to_string()
+                } else {
+                    "Rename was not attempted".to_string()
+                },
+            })
+        })
+        .collect()
+}
+
+fn rollback(done: &[Step]) -> Vec<RenameError> {
+    undo_steps(done)
+        .into_iter()
+        .map(|(_, error)| error)
+        .collect()
+}
+
+// 実行した手順を逆順に元へ戻す。戻せなかった手順の entry とエラーを返す
+fn undo_steps(done: &[Step]) -> Vec<(usize, RenameError)> {
+    let mut errors = Vec::new();
+    for mv in done.iter().rev() {
+        debug!(from:% = mv.to.display(), to:% = mv.from.display(); "Rolling back");
+        // 元の名前が他のファイルに使われていれば、上書きせずに今の場所 (一時的な名前など) に残す
+        let result = if fs::symlink_metadata(&mv.from).is_ok() {
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "'{}' is in use, so the file was left at this path",
+                    mv.from.display()
+                ),
+            ))
+        } else {
+            fs::rename(&mv.to, &mv.from)
+        };
+        if let Err(e) = result {
+            warn!(
+                path:% = mv.from.display(),
+                from:% = mv.to.display(),
+                error:% = e;
+                "Failed to restore"
+            );
+            errors.push((mv.entry, RenameError::io(&mv.to, &e)));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn mv(dir: &TempDir, from: &str, to: &str) -> Move {
+        Move {
+            from: dir.path(from),
+            to: dir.path(to),
+        }
+    }
+
+    fn paths(steps: &[Step]) -> Vec<(PathBuf, PathBuf)> {
+        steps
+            .iter()
+            .map(|step| (step.from.clone(), step.to.clone()))
+            .collect()
+    }
+
+    // 指定した件数の移動が終わったら中断を要求する
+    struct CancelAfter(usize, std::cell::Cell<bool>);
+
+    impl Observer for CancelAfter {
+        fn progress(&self, done: usize, _total: usize, _path: &Path) {
+            if done >= self.0 {
+                self.1.set(true);
+            }
+        }
+
+        fn is_cancelled(&self) -> bool {
+            self.1.get()
+        }
+    }
+
+    #[test]
+    fn plan_runs_a_chain_from_its_end() {
+        let dir = TempDir::new("plan-chain");
+        let moves = [mv(&dir, "1", "2"), mv(&dir, "2", "3")];
+        let steps = plan(&moves, &CaseFolding::default());
+        assert_eq!(
+            paths(&steps),
+            [
+                (dir.path("2"), dir.path("3")),
+                (dir.path("1"), dir.path("2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_parks_one_file_of_a_swap() {
+        let dir = TempDir::new("plan-swap");
+        let moves = [mv(&dir, "a", "b"), mv(&dir, "b", "a")];
+        let steps = plan(&moves, &CaseFolding::default());
+        assert_eq!(steps.len(), 3);
+        let temp = steps[0].to.clone();
+        assert_eq!(
+            paths(&steps),
+            [
+                (dir.path("a"), temp.clone()),
+                (dir.path("b"), dir.path("a")),
+                (temp, dir.path("b")),
+            ]
+        );
+        // 循環は1つのまとまりとして扱う
+        assert!(steps.iter().all(|step| step.group == steps[0].group));
+    }
+
+    #[test]
+    fn plan_renames_directory_contents_first() {
+        let dir = TempDir::new("plan-nested");
+        let moves = [mv(&dir, "d", "e"), mv(&dir, "d/f", "d/g")];
+        let steps = plan(&moves, &CaseFolding::default());
+        assert_eq!(
+            paths(&steps),
+            [
+                (dir.path("d/f"), dir.path("d/g")),
+                (dir.path("d"), dir.path("e")),
+            ]
+        );
+    }
+
+    #[test]
+    fn execute_chain_and_swap() {
+        let dir = TempDir::new("execute-chain-swap");
+        for name in ["1", "2", "a", "b"] {
+            dir.write(name, name);
+        }
+        let moves = [
+            mv(&dir, "1", "2"),
+            mv(&dir, "2", "3"),
+            mv(&dir, "a", "b"),
+            mv(&dir, "b", "a"),
+        ];
+        let outcomes = execute(&moves, false, &()).unwrap();
+
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, RenameOutcome::Renamed { .. })));
+        assert!(!dir.path("1").exists());
+        assert_eq!(dir.read("2"), "1");
+        assert_eq!(dir.read("3"), "2");
+        assert_eq!(dir.read("a"), "b");
+        assert_eq!(dir.read("b"), "a");
+        // 一時的な名前のファイルは残らない
+        assert_eq!(fs::read_dir(dir.root()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn execute_directory_and_its_contents() {
+        let dir = TempDir::new("execute-nested");
+        dir.write("d/f", "f");
+        let moves = [mv(&dir, "d", "e"), mv(&dir, "d/f", "d/g")];
+        let outcomes = execute(&moves, false, &()).unwrap();
+
+        assert_eq!(dir.read("e/g"), "f");
+        assert!(!dir.path("d").exists());
+        match &outcomes[1] {
+            RenameOutcome::Renamed { new_path, .. } => assert_eq!(new_path, &dir.path("e/g")),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn execute_rolls_back_after_a_failure() {
+        let dir = TempDir::new("execute-rollback");
+        for name in ["1", "2", "x"] {
+            dir.write(name, name);
+        }
+        // 移動先のディレクトリがないので、最後の移動だけが失敗する
+        let moves = [
+            mv(&dir, "1", "2"),
+            mv(&dir, "2", "3"),
+            mv(&dir, "x", "missing/x"),
+        ];
+        match execute(&moves, false, &()) {
+            Err(RenameError::Failed {
+                index, rolled_back, ..
+            }) => {
+                assert_eq!(index, 2);
+                assert!(rolled_back);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        for name in ["1", "2", "x"] {
+            assert_eq!(dir.read(name), name);
+        }
+        assert!(!dir.path("3").exists());
+    }
+
+    #[test]
+    fn execute_removes_created_directories_on_rollback() {
+        let dir = TempDir::new("execute-rollback-directories");
+        dir.write("a", "a");
+        dir.write("g/k", "k");
+        // g を自身の中へは移動できない
+        let moves = [mv(&dir, "a", "sub/a"), mv(&dir, "g", "g/k/g")];
+        assert!(execute(&moves, true, &()).is_err());
+        assert_eq!(dir.read("a"), "a");
+        assert!(!dir.path("sub").exists());
+    }
+
+    #[test]
+    fn execute_rolls_back_when_cancelled() {
+        let dir = TempDir::new("execute-cancel");
+        for name in ["a", "b", "c"] {
+            dir.write(name, name);
+        }
+        let moves = [mv(&dir, "a", "x"), mv(&dir, "b", "y"), mv(&dir, "c", "z")];
+        let observer = CancelAfter(1, Default::default());
+        assert!(matches!(
+            execute(&moves, false, &observer),
+            Err(RenameError::Cancelled {
+                rolled_back: true,
+                ..
+            })
+        ));
+        for name in ["a", "b", "c"] {
+            assert_eq!(dir.read(name), name);
+        }
+    }
+
+    #[test]
+    fn conflicts_with_existing_files_and_within_the_batch() {
+        let dir = TempDir::new("conflicts");
+        for name in ["a", "b", "c", "taken"] {
+            dir.write(name, name);
+        }
+        let moves = [
+            mv(&dir, "a", "taken"),
+            mv(&dir, "b", "same"),
+            mv(&dir, "c", "same"),
+        ];
+        let conflicts = check_conflicts(&moves, &CaseFolding::default());
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].index, 0);
+        assert!(matches!(conflicts[0].reason, ConflictReason::AlreadyExists));
+        assert_eq!(conflicts[1].index, 2);
+        assert!(matches!(
+            conflicts[1].reason,
+            ConflictReason::DuplicateTarget
+        ));
+
+        // 入れ替えは衝突しない
+        let swap = [mv(&dir, "a", "b"), mv(&dir, "b", "a")];
+        assert!(check_conflicts(&swap, &CaseFolding::default()).is_empty());
+    }
+
+    #[test]
+    fn hard_link_to_the_same_file_is_a_conflict() {
+        let dir = TempDir::new("conflicts-hard-link");
+        dir.write("a", "a");
+        fs::hard_link(dir.path("a"), dir.path("b")).unwrap();
+        let moves = [mv(&dir, "a", "b")];
+        let folding = CaseFolding::default();
+
+        let conflicts = check_conflicts(&moves, &folding);
+        assert_eq!(conflicts.len(), 1);
+        assert!(matches!(conflicts[0].reason, ConflictReason::AlreadyExists));
+        // 一時的な名前を経由すると、rename が何もせずに元の名前だけが消える
+        assert_eq!(plan(&moves, &folding).len(), 1);
+        assert!(execute(&moves, false, &()).is_err());
+        assert_eq!(dir.read("a"), "a");
+        assert_eq!(fs::read_dir(dir.root()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn conflicts_with_a_directory_the_batch_creates() {
+        let dir = TempDir::new("conflicts-directories");
+        dir.write("a", "a");
+        dir.write("b", "b");
+        let moves = [mv(&dir, "a", "sub/a"), mv(&dir, "b", "sub")];
+        let conflicts = check_conflicts(&moves, &CaseFolding::default());
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].index, 1);
+        assert!(matches!(
+            conflicts[0].reason,
+            ConflictReason::DuplicateTarget
+        ));
+    }
+
+    #[test]
+    fn best_effort_rolls_back_a_cycle_when_a_member_fails() {
+        let dir = TempDir::new("cycle-failure");
+        dir.write("m/n/s", "s");
+        dir.write("g/k", "k");
+
+        // g を自身の中の g/k へは移動できないので、循環の途中で失敗する
+        let moves = [
+            mv(&dir, "m/n/s", "g"),
+            mv(&dir, "g", "g/k"),
+            mv(&dir, "g/k", "m/n/s"),
+        ];
+        let outcomes = execute_best_effort(&moves, false, &());
+
+        assert!(matches!(outcomes[1], RenameOutcome::Failed { .. }));
+        for index in [0, 2] {
+            assert!(
+                matches!(outcomes[index], RenameOutcome::Skipped { .. }),
+                "{:?}",
+                outcomes[index]
+            );
+        }
+        assert_eq!(dir.read("m/n/s"), "s");
+        assert_eq!(dir.read("g/k"), "k");
+        assert_eq!(fs::read_dir(dir.path("m/n")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rollback_does_not_overwrite_an_occupied_name() {
+        let dir = TempDir::new("occupied-rollback");
+        dir.write("temp", "parked");
+        dir.write("a", "other");
+
+        let step = Step {
+            entry: 0,
+            group: 0,
+            from: dir.path("a"),
+            to: dir.path("temp"),
+        };
+        let errors = undo_steps(&[step]);
+
+        assert!(matches!(
+            errors.as_slice(),
+            [(0, RenameError::AlreadyExists(_))]
+        ));
+        assert_eq!(dir.read("a"), "other");
+        assert_eq!(dir.read("temp"), "parked");
+    }
+}
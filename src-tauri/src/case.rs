@@ -0,0 +1,170 @@
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Case {
+    Lower,
+    Upper,
+    // 単語の区切りはそのまま残し、各単語の先頭だけを大文字にする
+    Title,
+    Camel,
+    Pascal,
+    Snake,
+    Kebab,
+}
+
+// 名前のどの部分を変換するか
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum CaseTarget {
+    #[default]
+    Stem,
+    Extension,
+    Both,
+}
+
+impl CaseTarget {
+    pub fn includes_stem(self) -> bool {
+        self != CaseTarget::Extension
+    }
+
+    pub fn includes_extension(self) -> bool {
+        self != CaseTarget::Stem
+    }
+}
+
+/// `text` を指定された書き方に変換する。大文字・小文字の判定と変換は Unicode の規則に従う。
+pub fn convert(text: &str, case: Case) -> String {
+    match case {
+        Case::Lower => text.to_lowercase(),
+        Case::Upper => text.to_uppercase(),
+        Case::Title => title(text),
+        Case::Camel | Case::Pascal | Case::Snake | Case::Kebab => {
+            // `.gitignore` のような先頭のドットは残す
+            let rest = text.trim_start_matches('.');
+            let prefix = &text[..text.len() - rest.len()];
+            let words = words(rest);
+            let joined = match case {
+                Case::Snake => join_lower(&words, "_"),
+                Case::Kebab => join_lower(&words, "-"),
+                Case::Camel => words
+                    .iter()
+                    .enumerate()
+                    .map(|(i, word)| match i {
+                        0 => word.to_lowercase(),
+                        _ => capitalize(word),
+                    })
+                    .collect(),
+                _ => words.iter().map(|word| capitalize(word)).collect(),
+            };
+            format!("{}{}", prefix, joined)
+        }
+    }
+}
+
+fn join_lower(words: &[String], separator: &str) -> String {
+    words
+        .iter()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn title(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars() {
+        // アポストロフィは単語の一部とみなす (Don't → Don't)
+        if c.is_alphanumeric() || c == '\'' || c == '’' {
+            word.push(c);
+        } else {
+            result.push_str(&capitalize(&word));
+            word.clear();
+            result.push(c);
+        }
+    }
+    result.push_str(&capitalize(&word));
+    result
+}
+
+// 先頭の文字をタイトルケースに、残りを小文字にする
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => {
+            let mut result = titlecase(first);
+            result.push_str(&chars.as_str().to_lowercase());
+            result
+        }
+        None => String::new(),
+    }
+}
+
+// 合字の大文字には先頭だけが大文字の形 (ǅ など) があるので、それを使う
+fn titlecase(c: char) -> String {
+    match c {
+        'Ǆ' | 'ǅ' | 'ǆ' => "ǅ".to_string(),
+        'Ǉ' | 'ǈ' | 'ǉ' => "ǈ".to_string(),
+        'Ǌ' | 'ǋ' | 'ǌ' => "ǋ".to_string(),
+        'Ǳ' | 'ǲ' | 'ǳ' => "ǲ".to_string(),
+        _ => c.to_uppercase().collect(),
+    }
+}
+
+/// 英数字以外の文字と、小文字から大文字への切り替わり (`fileName`, `HTMLFile`) で単語に分ける。
+fn words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if !current.is_empty() && c.is_uppercase() {
+            let previous = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if previous.is_lowercase()
+                || previous.is_numeric()
+                || (previous.is_uppercase() && next_is_lower)
+            {
+                words.push(std::mem::take(&mut current));
+            }
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_non_ascii_letters() {
+        assert_eq!(convert("straße", Case::Upper), "STRASSE");
+        assert_eq!(convert("ÉCOLE", Case::Lower), "école");
+        // 語末のシグマは ς になる
+        assert_eq!(convert("ΣΊΣΥΦΟΣ", Case::Lower), "σίσυφος");
+        assert_eq!(convert("日本語", Case::Upper), "日本語");
+    }
+
+    #[test]
+    fn title_case_uses_titlecase_forms() {
+        assert_eq!(convert("élan VITAL", Case::Title), "Élan Vital");
+        assert_eq!(convert("ǆungla", Case::Title), "ǅungla");
+        assert_eq!(convert("don't stop", Case::Title), "Don't Stop");
+    }
+
+    #[test]
+    fn splits_words_in_any_script() {
+        assert_eq!(convert("Über Größe", Case::Snake), "über_größe");
+        assert_eq!(convert("dateiÜberGröße", Case::Kebab), "datei-über-größe");
+        assert_eq!(convert("naïve café", Case::Camel), "naïveCafé");
+        assert_eq!(convert(".ñandú grande", Case::Pascal), ".ÑandúGrande");
+        assert_eq!(convert("HTMLFile", Case::Snake), "html_file");
+    }
+}
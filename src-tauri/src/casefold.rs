@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::normalization::{self, NormalizationForm};
+
+// 調べられなかった場合の既定値。Windows と macOS は通常、大文字と小文字を区別しない
+const DEFAULT_CASE_SENSITIVE: bool = !cfg!(any(windows, target_os = "macos"));
+
+/// ディレクトリごとに、ファイルシステムが大文字と小文字を区別するかを記録する。
+///
+/// FAT や NTFS をマウントした場合など、同じ OS でもディレクトリによって異なることがある。
+#[derive(Default)]
+pub struct CaseFolding {
+    sensitive: HashMap<PathBuf, bool>,
+}
+
+impl CaseFolding {
+    /// `paths` の親ディレクトリをすべて調べる。
+    pub fn for_paths<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let mut folding = Self::default();
+        for path in paths {
+            if let Some(dir) = path.parent() {
+                if !folding.sensitive.contains_key(dir) {
+                    folding
+                        .sensitive
+                        .insert(dir.to_path_buf(), is_case_sensitive(dir));
+                }
+            }
+        }
+        folding
+    }
+
+    fn is_sensitive(&self, dir: &Path) -> bool {
+        self.sensitive
+            .get(dir)
+            .copied()
+            .unwrap_or(DEFAULT_CASE_SENSITIVE)
+    }
+
+    /// 同じファイルを指すパスが同じ値になるように、区別しないディレクトリでは名前を小文字にする。
+    pub fn key(&self, path: &Path) -> PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) if !self.is_sensitive(dir) => {
+                dir.join(name.to_string_lossy().to_lowercase())
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// 同じエントリを指す別の名前への移動か。大文字と小文字を区別しないファイルシステムでの
+    /// 大文字と小文字だけの変更や、APFS での Unicode の正規化だけの変更がこれにあたる。
+    ///
+    /// ハードリンクは同じ inode を指していても別の名前なので含めない。
+    pub fn is_same_entry_rename(&self, from: &Path, to: &Path) -> bool {
+        from != to && (self.key(from) == self.key(to) || same_normalized_name(from, to))
+    }
+}
+
+/// 同じディレクトリにあり、NFC にすると同じ名前になるか。
+pub fn same_normalized_name(a: &Path, b: &Path) -> bool {
+    let nfc = |name: &std::ffi::OsStr| {
+        normalization::normalize(&name.to_string_lossy(), NormalizationForm::Nfc)
+    };
+    match (a.file_name(), b.file_name()) {
+        (Some(x), Some(y)) => a.parent() == b.parent() && nfc(x) == nfc(y),
+        _ => false,
+    }
+}
+
+/// `dir` のファイルシステムが大文字と小文字を区別するか調べる。
+///
+/// 既存のエントリの大文字と小文字を入れ替えた名前で、同じエントリが見つかるかで判定する。
+/// 判定に使えるエントリがなければ OS の既定値を返す。
+pub fn is_case_sensitive(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return DEFAULT_CASE_SENSITIVE;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let swapped = swap_case(&name);
+        if swapped == name {
+            continue;
+        }
+        let other = dir.join(&swapped);
+        if fs::symlink_metadata(&other).is_err() {
+            return true;
+        }
+        return !same_entry(&entry.path(), &other).unwrap_or(true);
+    }
+    DEFAULT_CASE_SENSITIVE
+}
+
+fn swap_case(name: &str) -> String {
+    let mut swapped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_lowercase() {
+            swapped.extend(c.to_uppercase());
+        } else {
+            swapped.extend(c.to_lowercase());
+        }
+    }
+    swapped
+}
+
+/// `a` と `b` が同じエントリを指すか。判定できない場合は `None`。
+#[cfg(unix)]
+pub fn same_entry(a: &Path, b: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let a = fs::symlink_metadata(a).ok()?;
+    let b = fs::symlink_metadata(b).ok()?;
+    Some(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// inode を比べられない
+#[cfg(not(unix))]
+pub fn same_entry(_a: &Path, _b: &Path) -> Option<bool> {
+    None
+}
@@ -0,0 +1,227 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::batch::Conflict;
+use crate::template::TemplateError;
+use crate::validation::NameViolations;
+
+// OS のエラーの詳細
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OsError {
+    pub path: Option<PathBuf>,
+    pub os_code: Option<i32>,
+    pub message: String,
+}
+
+/// すべてのコマンドが返すエラー。フロントエンドでは `kind` で種類を判別する。
+#[derive(serde::Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum RenameError {
+    NotFound(OsError),
+    PermissionDenied(OsError),
+    AlreadyExists(OsError),
+    // 上記以外の入出力エラー
+    Io(OsError),
+    #[serde(rename_all = "camelCase")]
+    InvalidPattern {
+        message: String,
+    },
+    // position はテンプレートの先頭からの文字数
+    #[serde(rename_all = "camelCase")]
+    InvalidTemplate {
+        position: usize,
+        message: String,
+    },
+    // 連番の設定が不正
+    #[serde(rename_all = "camelCase")]
+    InvalidSequence {
+        message: String,
+    },
+    // index 番目の操作が不正
+    #[serde(rename_all = "camelCase")]
+    InvalidOperation {
+        index: usize,
+        cause: Box<RenameError>,
+    },
+    // 読み込んだディレクトリの外にあるファイル
+    #[serde(rename_all = "camelCase")]
+    OutsideRoot {
+        path: PathBuf,
+        root: PathBuf,
+    },
+    // 読み込んでいないディレクトリを root としてリネームしようとした
+    #[serde(rename_all = "camelCase")]
+    NotScanned {
+        root: PathBuf,
+    },
+    // 新しい名前が元のディレクトリの外を指している
+    #[serde(rename_all = "camelCase")]
+    EscapesDirectory {
+        path: PathBuf,
+        new_name: String,
+    },
+    // ファイルに触れる前に検出した不正な名前
+    #[serde(rename_all = "camelCase")]
+    InvalidNames {
+        names: Vec<NameViolations>,
+    },
+    // 移動元のファイルが見つからない
+    #[serde(rename_all = "camelCase")]
+    Missing {
+        paths: Vec<PathBuf>,
+    },
+    // ファイルに触れる前に検出した衝突
+    #[serde(rename_all = "camelCase")]
+    Conflicts {
+        conflicts: Vec<Conflict>,
+    },
+    // index 番目の移動に失敗し、それ以前の移動を元に戻した結果
+    #[serde(rename_all = "camelCase")]
+    Failed {
+        index: usize,
+        path: PathBuf,
+        cause: Box<RenameError>,
+        rolled_back: bool,
+        rollback_errors: Vec<RenameError>,
+    },
+    // 実行中に中断され、それまでの移動を元に戻した結果
+    #[serde(rename_all = "camelCase")]
+    Cancelled {
+        rolled_back: bool,
+        rollback_errors: Vec<RenameError>,
+    },
+    #[serde(rename_all = "camelCase")]
+    NothingToUndo {
+        message: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    NothingToRedo {
+        message: String,
+    },
+}
+
+impl RenameError {
+    /// `path` に対する操作で発生した入出力エラーを種類ごとに分類する。
+    pub fn io(path: &Path, error: &io::Error) -> Self {
+        Self::from_io(Some(path.to_path_buf()), error, error.to_string())
+    }
+
+    fn from_io(path: Option<PathBuf>, error: &io::Error, message: String) -> Self {
+        let os_error = OsError {
+            path,
+            os_code: error.raw_os_error(),
+            message,
+        };
+        match error.kind() {
+            io::ErrorKind::NotFound => RenameError::NotFound(os_error),
+            io::ErrorKind::PermissionDenied => RenameError::PermissionDenied(os_error),
+            io::ErrorKind::AlreadyExists => RenameError::AlreadyExists(os_error),
+            _ => RenameError::Io(os_error),
+        }
+    }
+}
+
+// 内部で context を付けたエラーは、根本の io::Error で分類しメッセージには経緯をすべて含める
+impl From<anyhow::Error> for RenameError {
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        match error.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+            Some(io_error) => Self::from_io(None, io_error, message),
+            None => RenameError::Io(OsError {
+                path: None,
+                os_code: None,
+                message,
+            }),
+        }
+    }
+}
+
+impl From<regex::Error> for RenameError {
+    fn from(error: regex::Error) -> Self {
+        RenameError::InvalidPattern {
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<TemplateError> for RenameError {
+    fn from(error: TemplateError) -> Self {
+        RenameError::InvalidTemplate {
+            position: error.position,
+            message: error.message,
+        }
+    }
+}
+
+impl std::fmt::Display for RenameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenameError::NotFound(e)
+            | RenameError::PermissionDenied(e)
+            | RenameError::AlreadyExists(e)
+            | RenameError::Io(e) => match &e.path {
+                Some(path) => write!(f, "{}: {}", path.display(), e.message),
+                None => write!(f, "{}", e.message),
+            },
+            RenameError::InvalidPattern { message } => write!(f, "Invalid pattern: {}", message),
+            RenameError::InvalidTemplate { position, message } => {
+                write!(f, "Invalid template at position {}: {}", position, message)
+            }
+            RenameError::InvalidSequence { message } => {
+                write!(f, "Invalid numbering: {}", message)
+            }
+            RenameError::InvalidOperation { index, cause } => {
+                write!(f, "Step {}: {}", index + 1, cause)
+            }
+            RenameError::OutsideRoot { path, root } => {
+                write!(f, "'{}' is outside of '{}'", path.display(), root.display())
+            }
+            RenameError::NotScanned { root } => {
+                write!(f, "'{}' has not been loaded", root.display())
+            }
+            RenameError::EscapesDirectory { path, new_name } => write!(
+                f,
+                "New name '{}' would move '{}' out of its folder",
+                new_name,
+                path.display()
+            ),
+            RenameError::InvalidNames { names } => {
+                write!(f, "{} file(s) have an invalid new name", names.len())
+            }
+            RenameError::Missing { paths } => {
+                write!(f, "{} file(s) no longer exist", paths.len())
+            }
+            RenameError::Conflicts { conflicts } => {
+                write!(
+                    f,
+                    "{} file(s) would overwrite an existing file",
+                    conflicts.len()
+                )
+            }
+            RenameError::Failed {
+                path,
+                cause,
+                rolled_back,
+                ..
+            } => {
+                write!(f, "Failed to rename '{}': {}", path.display(), cause)?;
+                if *rolled_back {
+                    write!(f, " (all changes were rolled back)")
+                } else {
+                    write!(f, " (rollback was incomplete)")
+                }
+            }
+            RenameError::Cancelled { rolled_back, .. } => {
+                if *rolled_back {
+                    write!(f, "Rename was cancelled (all changes were rolled back)")
+                } else {
+                    write!(f, "Rename was cancelled (rollback was incomplete)")
+                }
+            }
+            RenameError::NothingToUndo { message } | RenameError::NothingToRedo { message } => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::batch::{self, Move};
+
+const JOURNAL_FILE_NAME: &str = "rename-journal.json";
+
+// 実行前の状態で表した移動。ディレクトリ内のファイルは親ディレクトリが移動する前のパスになる
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+// rename_files 1回分の記録
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalBatch {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub rule: Option<serde_json::Value>,
+    pub renames: Vec<JournalEntry>,
+    // サブフォルダへの移動のために作ったディレクトリ。作成した順
+    #[serde(default)]
+    pub created_directories: Vec<PathBuf>,
+}
+
+impl JournalBatch {
+    /// 元に戻すための移動。現在の状態で表し、ファイルを元の親ディレクトリ内の元の名前へ戻す
+    pub fn undo_moves(&self) -> Vec<Move> {
+        let moves = self.redo_moves();
+        batch::final_paths(&moves)
+            .into_iter()
+            .zip(&moves)
+            .map(|(current, mv)| Move {
+                from: current,
+                to: batch::relocate(&moves, &mv.from),
+            })
+            .collect()
+    }
+
+    pub fn redo_moves(&self) -> Vec<Move> {
+        self.renames
+            .iter()
+            .map(|entry| Move {
+                from: entry.from.clone(),
+                to: entry.to.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Journal {
+    // 古い順。末尾が最後に実行したバッチ
+    pub done: Vec<JournalBatch>,
+    // 末尾が最後に取り消したバッチ
+    pub undone: Vec<JournalBatch>,
+}
+
+pub fn journal_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(JOURNAL_FILE_NAME)
+}
+
+impl Journal {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("failed to parse rename journal {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("failed to read rename journal {}", path.display()))
+            }
+        }
+    }
+
+    // 書き込み途中で壊れないよう、一時ファイルに書いてから置き換える。
+    // 一時ファイルの名前は固定なので、同じ履歴への保存を並行して行わないこと
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, contents)
+            .with_context(|| format!("failed to write {}", temp.display()))?;
+        fs::rename(&temp, path)
+            .with_context(|| format!("failed to replace rename journal {}", path.display()))
+    }
+
+    /// 新しいバッチを記録する。やり直し用の履歴は破棄される。
+    pub fn record(
+        &mut self,
+        moves: &[Move],
+        created_directories: Vec<PathBuf>,
+        rule: Option<serde_json::Value>,
+    ) {
+        let id = self
+            .done
+            .iter()
+            .chain(self.undone.iter())
+            .map(|batch| batch.id)
+            .max()
+            .map_or(1, |id| id + 1);
+        self.undone.clear();
+        self.done.push(JournalBatch {
+            id,
+            timestamp: Utc::now(),
+            rule,
+            renames: moves
+                .iter()
+                .filter(|mv| mv.from != mv.to)
+                .map(|mv| JournalEntry {
+                    from: mv.from.clone(),
+                    to: mv.to.clone(),
+                })
+                .collect(),
+            created_directories,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{execute, RenameOutcome};
+    use crate::testing::TempDir;
+
+    #[test]
+    fn undo_moves_are_expressed_in_the_current_state() {
+        let dir = TempDir::new("journal-undo");
+        dir.write("d/f", "f");
+        let moves = [
+            Move {
+                from: dir.path("d"),
+                to: dir.path("e"),
+            },
+            Move {
+                from: dir.path("d/f"),
+                to: dir.path("d/g"),
+            },
+        ];
+        let mut journal = Journal::default();
+        journal.record(&moves, Vec::new(), None);
+        let batch = journal.done.last().unwrap();
+
+        let undo: Vec<(PathBuf, PathBuf)> = batch
+            .undo_moves()
+            .into_iter()
+            .map(|mv| (mv.from, mv.to))
+            .collect();
+        assert_eq!(
+            undo,
+            [
+                (dir.path("e"), dir.path("d")),
+                (dir.path("e/g"), dir.path("e/f")),
+            ]
+        );
+
+        // 実行して元に戻すと、最初の状態になる
+        execute(&batch.redo_moves(), false, &()).unwrap();
+        assert_eq!(dir.read("e/g"), "f");
+        let outcomes = execute(&batch.undo_moves(), false, &()).unwrap();
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, RenameOutcome::Renamed { .. })));
+        assert_eq!(dir.read("d/f"), "f");
+        assert!(!dir.path("e").exists());
+    }
+
+    #[test]
+    fn recording_clears_the_redo_history() {
+        let mut journal = Journal::default();
+        let moves = [Move {
+            from: PathBuf::from("/a"),
+            to: PathBuf::from("/b"),
+        }];
+        journal.record(&moves, Vec::new(), None);
+        let batch = journal.done.pop().unwrap();
+        journal.undone.push(batch);
+        journal.record(&moves, Vec::new(), None);
+        assert!(journal.undone.is_empty());
+        assert_eq!(journal.done.last().unwrap().id, 2);
+    }
+}
@@ -1,13 +1,58 @@
 use chrono::{DateTime, Utc};
-use std::fs;
-use std::path::PathBuf;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use tauri::ipc::Channel;
+use tauri::{Emitter, Manager};
+use tauri_plugin_log::{RotationStrategy, Target, TargetKind, TimezoneStrategy};
+
+mod batch;
+mod case;
+mod casefold;
+mod error;
+mod journal;
+mod logging;
+mod normalization;
+mod pipeline;
+mod rename;
+pub mod scan;
+mod sequence;
+mod template;
+mod validation;
+
+#[cfg(test)]
+mod testing;
+
+use batch::{Move, RenameMode, RenameOptions, RenameOutcome};
+use error::RenameError;
+use journal::{Journal, JournalBatch};
+use pipeline::RenamePipeline;
+use rename::RenamePlan;
+use scan::{EntryKind, EntryMetadata, ScanOptions, ScanResult, ScanSummary};
+use validation::NameViolations;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct FileEntry {
     name: String,
     path: PathBuf,
+    // 読み込んだディレクトリからの相対パス
+    #[serde(default)]
+    relative_path: PathBuf,
+    #[serde(default)]
+    kind: EntryKind,
     modified: DateTime<Utc>,
+    #[serde(flatten, default)]
+    metadata: EntryMetadata,
+    // 名前が NFC かどうか。読み込み時に確認した場合のみ
+    #[serde(default)]
+    is_nfc: Option<bool>,
     new_name: Option<String>, // Add this field
 }
 
@@ -21,78 +66,510 @@ pub struct RenameFileEntry {
     new_name: String, // 必須フィールド
 }
 
+// 実行中のリネームや読み込みの中断フラグ。キーはフロントエンドが決めたタスク ID
+#[derive(Default)]
+struct Tasks(Mutex<HashMap<String, Arc<AtomicBool>>>);
+
+impl Tasks {
+    fn tokens(&self) -> MutexGuard<'_, HashMap<String, Arc<AtomicBool>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // ID がなければ中断できないフラグを返す
+    fn register(&self, task_id: Option<&str>) -> Arc<AtomicBool> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        if let Some(id) = task_id {
+            self.tokens().insert(id.to_string(), cancelled.clone());
+        }
+        cancelled
+    }
+
+    fn finish(&self, task_id: Option<&str>) {
+        if let Some(id) = task_id {
+            self.tokens().remove(id);
+        }
+    }
+
+    fn cancel(&self, task_id: &str) -> bool {
+        match self.tokens().get(task_id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// 読み込んだディレクトリ (正規化済み)。リネームはこの中のファイルだけに限る
+#[derive(Default)]
+struct ScannedRoots(Mutex<HashSet<PathBuf>>);
+
+impl ScannedRoots {
+    fn roots(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn insert(&self, path: &Path) -> Result<(), RenameError> {
+        let root = std::fs::canonicalize(path).map_err(|e| RenameError::io(path, &e))?;
+        self.roots().insert(root);
+        Ok(())
+    }
+
+    // 読み込んだことのあるディレクトリなら、正規化したパスを返す
+    fn check(&self, path: &Path) -> Result<PathBuf, RenameError> {
+        let not_scanned = || RenameError::NotScanned {
+            root: path.to_path_buf(),
+        };
+        let root = std::fs::canonicalize(path).map_err(|_| not_scanned())?;
+        if self.roots().contains(&root) {
+            Ok(root)
+        } else {
+            Err(not_scanned())
+        }
+    }
+}
+
 #[tauri::command]
-fn read_files_in_directory(path: PathBuf) -> Result<Vec<FileEntry>, String> {
-    let mut entries: Vec<FileEntry> = Vec::new();
-    for entry_result in fs::read_dir(path).map_err(|e| e.to_string())? {
-        let entry = entry_result.map_err(|e| e.to_string())?;
-        let file_type = entry.file_type().map_err(|e| e.to_string())?;
-
-        if file_type.is_file() {
-            let metadata = entry.metadata().map_err(|e| e.to_string())?;
-            let modified: DateTime<Utc> = metadata.modified().map_err(|e| e.to_string())?.into();
-            entries.push(FileEntry {
-                name: entry.file_name().to_string_lossy().into_owned(),
-                path: entry.path(),
-                modified,
-                new_name: None,
-            });
+fn read_files_in_directory(
+    roots: tauri::State<'_, ScannedRoots>,
+    path: PathBuf,
+    options: Option<ScanOptions>,
+) -> Result<ScanResult, RenameError> {
+    roots.insert(&path)?;
+    scan::scan_directory(&path, &options.unwrap_or_default())
+}
+
+// 一度に送るエントリと警告の数の既定値
+const SCAN_BATCH_SIZE: usize = 500;
+
+// 読み込んだエントリを channel へ少しずつ送る。中断されるか channel が閉じられたら読み込みをやめる
+#[tauri::command]
+async fn scan_directory_stream(
+    tasks: tauri::State<'_, Tasks>,
+    roots: tauri::State<'_, ScannedRoots>,
+    path: PathBuf,
+    options: Option<ScanOptions>,
+    channel: Channel<ScanResult>,
+    batch_size: Option<usize>,
+    task_id: Option<String>,
+) -> Result<ScanSummary, RenameError> {
+    roots.insert(&path)?;
+    let cancelled = tasks.register(task_id.as_deref());
+    let result =
+        tauri::async_runtime::spawn_blocking(move || {
+            let options = options.unwrap_or_default();
+            let batch_size = batch_size.unwrap_or(SCAN_BATCH_SIZE);
+            scan::scan_directory_batches(&path, &options, batch_size, &cancelled, |batch| {
+                match channel.send(batch) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(e) => {
+                        warn!(error:% = e; "Failed to send scan results");
+                        ControlFlow::Break(())
+                    }
+                }
+            })
+        })
+        .await;
+    tasks.finish(task_id.as_deref());
+    result.map_err(|e| anyhow::anyhow!("scan task failed: {}", e))?
+}
+
+#[tauri::command]
+fn cancel_scan(tasks: tauri::State<'_, Tasks>, task_id: String) -> bool {
+    tasks.cancel(&task_id)
+}
+
+#[tauri::command]
+fn preview_renames(
+    files: Vec<FileEntry>,
+    pipeline: RenamePipeline,
+    options: Option<RenameOptions>,
+) -> Result<RenamePlan, RenameError> {
+    rename::build_plan(files, &pipeline, &options.unwrap_or_default())
+}
+
+const PROGRESS_EVENT: &str = "rename-progress";
+// 大量のファイルでイベントが溢れないよう、通知の間隔を空ける
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RenameProgress {
+    task_id: Option<String>,
+    done: usize,
+    total: usize,
+    // 移動し終えたファイルの元のパス
+    path: PathBuf,
+}
+
+// 進捗を rename-progress イベントとしてフロントエンドへ送る
+struct ProgressEmitter {
+    app: tauri::AppHandle,
+    task_id: Option<String>,
+    cancelled: Arc<AtomicBool>,
+    last: Cell<Option<Instant>>,
+}
+
+impl batch::Observer for ProgressEmitter {
+    fn progress(&self, done: usize, total: usize, path: &Path) {
+        // 最後の1件は必ず送る
+        let recent = self
+            .last
+            .get()
+            .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL);
+        if recent && done < total {
+            return;
+        }
+        self.last.set(Some(Instant::now()));
+        let progress = RenameProgress {
+            task_id: self.task_id.clone(),
+            done,
+            total,
+            path: path.to_path_buf(),
+        };
+        if let Err(e) = self.app.emit(PROGRESS_EVENT, progress) {
+            warn!(error:% = e; "Failed to emit rename progress");
         }
     }
-    Ok(entries)
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// 大量のファイルでも UI を止めないよう、別スレッドで実行する
+#[tauri::command]
+async fn rename_files(
+    app: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+    roots: tauri::State<'_, ScannedRoots>,
+    root: PathBuf,
+    files: Vec<RenameFileEntry>,
+    rule: Option<serde_json::Value>,
+    options: Option<RenameOptions>,
+    task_id: Option<String>,
+) -> Result<Vec<RenameOutcome>, RenameError> {
+    // 呼び出し側が渡した root ではなく、実際に読み込んだディレクトリの中に限る
+    let root = roots.check(&root)?;
+    let cancelled = tasks.register(task_id.as_deref());
+    let observer = ProgressEmitter {
+        app: app.clone(),
+        task_id: task_id.clone(),
+        cancelled,
+        last: Cell::new(None),
+    };
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        rename_batch(
+            &app,
+            root,
+            files,
+            rule,
+            options.unwrap_or_default(),
+            &observer,
+        )
+    })
+    .await;
+    tasks.finish(task_id.as_deref());
+    result.map_err(|e| anyhow::anyhow!("rename task failed: {}", e))?
 }
 
+// 実行中のリネームに中断を要求する。該当するタスクがなければ false を返す
 #[tauri::command]
-fn rename_files(files: Vec<RenameFileEntry>) -> Result<(), String> {
-    println!("rename_files called with {} files", files.len());
+fn cancel_rename(tasks: tauri::State<'_, Tasks>, task_id: String) -> bool {
+    tasks.cancel(&task_id)
+}
 
+// root は ScannedRoots で確認した、正規化済みのパス
+fn rename_batch(
+    app: &tauri::AppHandle,
+    root: PathBuf,
+    files: Vec<RenameFileEntry>,
+    rule: Option<serde_json::Value>,
+    options: RenameOptions,
+    observer: &dyn batch::Observer,
+) -> Result<Vec<RenameOutcome>, RenameError> {
+    info!(
+        files = files.len(),
+        mode:? = options.mode,
+        subfolders = options.move_into_subfolders;
+        "Rename started"
+    );
+
+    // ファイルに触れる前に、読み込んだディレクトリの外へ出ないことと名前を検証する
+    let mut moves = Vec::with_capacity(files.len());
+    // moves の添字から files の添字への対応
+    let mut indices = Vec::with_capacity(files.len());
+    let mut rejected = Vec::new();
+    let mut invalid = Vec::new();
     for (index, file) in files.iter().enumerate() {
-        println!(
-            "Processing file {}: name='{}', new_name='{}'",
-            index, file.name, file.new_name
-        );
+        debug!(index, name:% = file.name, new_name:% = file.new_name; "Checking file");
 
-        if file.new_name.trim().is_empty() {
-            let error_msg = format!("New name is empty for file: {}", file.name);
-            println!("Error: {}", error_msg);
-            return Err(error_msg);
+        let to = match validation::destination_within(
+            &root,
+            &file.path,
+            &file.new_name,
+            options.move_into_subfolders,
+        ) {
+            Ok(to) => to,
+            Err(error) => {
+                warn!(path:% = file.path.display(), error:% = error; "Rejected destination");
+                match options.mode {
+                    RenameMode::Atomic => return Err(error),
+                    RenameMode::BestEffort => {
+                        rejected.push((index, file.path.clone(), error));
+                        continue;
+                    }
+                }
+            }
+        };
+        let violations = options.validate_name(&file.new_name);
+        if !violations.is_empty() {
+            warn!(path:% = file.path.display(), new_name:% = file.new_name; "Invalid new name");
+            invalid.push(NameViolations {
+                index,
+                path: file.path.clone(),
+                new_name: file.new_name.clone(),
+                violations,
+            });
+            continue;
         }
 
-        let new_path = file.path.with_file_name(&file.new_name);
-        println!(
-            "Renaming '{}' to '{}'",
-            file.path.display(),
-            new_path.display()
-        );
+        indices.push(index);
+        moves.push(Move {
+            from: file.path.clone(),
+            to,
+        });
+    }
+    if options.mode == RenameMode::Atomic && !invalid.is_empty() {
+        return Err(RenameError::InvalidNames { names: invalid });
+    }
 
-        match fs::rename(&file.path, &new_path) {
-            Ok(_) => println!(
-                "Successfully renamed '{}' to '{}'",
-                file.name, file.new_name
-            ),
-            Err(e) => {
-                let error_msg = format!(
-                    "Failed to rename '{}' to '{}': {}",
-                    file.name, file.new_name, e
-                );
-                println!("Error: {}", error_msg);
-                return Err(error_msg);
+    // 実行後に残っていれば、このバッチで作ったディレクトリ
+    let directories = batch::missing_directories(&moves);
+    let mut outcomes = match options.mode {
+        RenameMode::Atomic => batch::execute(&moves, options.move_into_subfolders, observer)
+            .inspect_err(|e| error!(error:% = e; "Rename failed"))?,
+        RenameMode::BestEffort => {
+            let mut outcomes =
+                batch::execute_best_effort(&moves, options.move_into_subfolders, observer);
+            // 衝突の添字は検証を通ったファイルだけの並びなので、files の並びに直す
+            for outcome in &mut outcomes {
+                if let RenameOutcome::Failed {
+                    error: RenameError::Conflicts { conflicts },
+                    ..
+                } = outcome
+                {
+                    for conflict in conflicts {
+                        conflict.index = indices[conflict.index];
+                    }
+                }
             }
+            outcomes
+        }
+    };
+    // 検証で弾いたファイルの結果を元の位置に戻す
+    rejected.extend(invalid.into_iter().map(|violations| {
+        (
+            violations.index,
+            violations.path.clone(),
+            RenameError::InvalidNames {
+                names: vec![violations],
+            },
+        )
+    }));
+    rejected.sort_by_key(|(index, _, _)| *index);
+    for (index, path, error) in rejected {
+        outcomes.insert(index, RenameOutcome::Failed { path, error });
+    }
+
+    // 履歴には実行前の状態で表した移動を残す
+    let applied: Vec<Move> = outcomes
+        .iter()
+        .zip(&files)
+        .filter(|(outcome, _)| matches!(outcome, RenameOutcome::Renamed { .. }))
+        .map(|(_, file)| Move {
+            from: file.path.clone(),
+            to: file.path.with_file_name(&file.new_name),
+        })
+        .collect();
+    info!(renamed = applied.len(), total = outcomes.len(); "Rename finished");
+    let created: Vec<PathBuf> = directories.into_iter().filter(|dir| dir.is_dir()).collect();
+    // リネーム自体は完了しているので、記録に失敗してもエラーにはしない
+    if !applied.is_empty() {
+        if let Err(e) = update_journal(app, |journal| {
+            journal.record(&applied, created, rule);
+            Ok(())
+        }) {
+            error!(error:% = e; "Failed to record rename journal");
         }
     }
+    Ok(outcomes)
+}
+
+// 履歴ファイルの読み込みから保存までを同時に1つだけにする。
+// 並行するリネームや取り消しが互いの記録を上書きしないように、保存が終わるまで保持する
+#[derive(Default)]
+struct JournalLock(Mutex<()>);
+
+impl JournalLock {
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
 
-    println!("All files renamed successfully");
-    Ok(())
+fn journal_file(app: &tauri::AppHandle) -> Result<PathBuf, RenameError> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| anyhow::anyhow!("failed to resolve the app data directory: {}", e))?;
+    Ok(journal::journal_path(&data_dir))
+}
+
+// 履歴を読み込み、f が成功した場合のみ保存する
+fn update_journal<T>(
+    app: &tauri::AppHandle,
+    f: impl FnOnce(&mut Journal) -> Result<T, RenameError>,
+) -> Result<T, RenameError> {
+    let path = journal_file(app)?;
+    let lock = app.state::<JournalLock>();
+    let _guard = lock.lock();
+    let mut journal = Journal::load(&path)?;
+    let result = f(&mut journal)?;
+    journal.save(&path)?;
+    Ok(result)
+}
+
+#[tauri::command]
+fn list_rename_history(
+    app: tauri::AppHandle,
+    lock: tauri::State<'_, JournalLock>,
+) -> Result<Journal, RenameError> {
+    let path = journal_file(&app)?;
+    let _guard = lock.lock();
+    Ok(Journal::load(&path)?)
+}
+
+// 最後のバッチを元に戻す。ファイルが記録どおりの場所にない場合は何もしない
+#[tauri::command]
+fn undo_last_rename(app: tauri::AppHandle) -> Result<JournalBatch, RenameError> {
+    update_journal(&app, |journal| {
+        let batch = journal
+            .done
+            .last()
+            .cloned()
+            .ok_or(RenameError::NothingToUndo {
+                message: "Nothing to undo".to_string(),
+            })?;
+        batch::execute(&batch.undo_moves(), false, &())?;
+        // 作ったディレクトリは空になったものだけを消す。失敗しても取り消し自体は完了している
+        batch::remove_directories(&batch.created_directories);
+        info!(batch = batch.id, files = batch.renames.len(); "Undid rename");
+        journal.done.pop();
+        journal.undone.push(batch.clone());
+        Ok(batch)
+    })
+}
+
+#[tauri::command]
+fn redo_rename(app: tauri::AppHandle) -> Result<JournalBatch, RenameError> {
+    update_journal(&app, |journal| {
+        let batch = journal
+            .undone
+            .last()
+            .cloned()
+            .ok_or(RenameError::NothingToRedo {
+                message: "Nothing to redo".to_string(),
+            })?;
+        batch::execute(
+            &batch.redo_moves(),
+            !batch.created_directories.is_empty(),
+            &(),
+        )?;
+        info!(batch = batch.id, files = batch.renames.len(); "Redid rename");
+        journal.undone.pop();
+        journal.done.push(batch.clone());
+        Ok(batch)
+    })
+}
+
+// get_recent_logs で返す行数の既定値
+const RECENT_LOG_LINES: usize = 500;
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RecentLogs {
+    // ログファイルのあるディレクトリ。不具合の報告にファイルごと添付できるように返す
+    directory: PathBuf,
+    lines: Vec<String>,
+}
+
+// ローテーションした古いファイルも含め、新しいものから lines 行を返す
+#[tauri::command]
+fn get_recent_logs(app: tauri::AppHandle, lines: Option<usize>) -> Result<RecentLogs, RenameError> {
+    let directory = app
+        .path()
+        .app_log_dir()
+        .map_err(|e| anyhow::anyhow!("failed to resolve the app log directory: {}", e))?;
+    let lines = logging::recent_lines(&directory, lines.unwrap_or(RECENT_LOG_LINES))
+        .map_err(|e| RenameError::io(&directory, &e))?;
+    Ok(RecentLogs { directory, lines })
+}
+
+// このアプリ自体のログのレベル。ファイルごとの詳細 (debug) は開発中だけ記録する。
+// リリースビルドで記録すると、大きなバッチ1回で残しておけるログがすべて押し出される
+const APP_LOG_LEVEL: log::LevelFilter = if cfg!(debug_assertions) {
+    log::LevelFilter::Debug
+} else {
+    log::LevelFilter::Info
+};
+
+// リリースビルドではコンソールがないので、ログディレクトリのファイルに書く。
+// 開発中は標準出力にも出すが、ファイルごとの詳細は含めない
+fn log_plugin<R: tauri::Runtime>() -> tauri::plugin::TauriPlugin<R> {
+    let mut targets = vec![Target::new(TargetKind::LogDir {
+        file_name: Some(logging::LOG_FILE_NAME.to_string()),
+    })];
+    if cfg!(debug_assertions) {
+        targets.push(
+            Target::new(TargetKind::Stdout).filter(|metadata| metadata.level() <= log::Level::Info),
+        );
+    }
+    tauri_plugin_log::Builder::new()
+        .targets(targets)
+        // 依存するクレートのログは警告以上だけにする
+        .level(log::LevelFilter::Warn)
+        .level_for(module_path!(), APP_LOG_LEVEL)
+        .max_file_size(logging::MAX_LOG_FILE_SIZE)
+        .rotation_strategy(RotationStrategy::KeepSome(logging::KEPT_LOG_FILES))
+        // ローテーションしたファイルの名前に使う。書式を上書きするので format より先に呼ぶ
+        .timezone_strategy(TimezoneStrategy::UseLocal)
+        .format(|out, message, record| {
+            out.finish(format_args!("{}", logging::format_line(message, record)))
+        })
+        .build()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
+        .plugin(log_plugin())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
+        .manage(Tasks::default())
+        .manage(ScannedRoots::default())
+        .manage(JournalLock::default())
         .invoke_handler(tauri::generate_handler![
             read_files_in_directory,
-            rename_files
+            scan_directory_stream,
+            cancel_scan,
+            preview_renames,
+            rename_files,
+            cancel_rename,
+            list_rename_history,
+            undo_last_rename,
+            redo_rename,
+            get_recent_logs
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,99 @@
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+
+// ログディレクトリに作るファイルの名前 (拡張子なし)
+pub const LOG_FILE_NAME: &str = "rename-app";
+// 1つのファイルの上限。超えたら日時付きの名前に変えて新しいファイルに書く
+pub const MAX_LOG_FILE_SIZE: u128 = 1024 * 1024;
+// 書き込み中のものも含めて残すファイルの数
+pub const KEPT_LOG_FILES: usize = 5;
+
+/// 1件のログを `日時 [レベル] [ターゲット] メッセージ key=value ...` の1行にする。
+pub fn format_line(message: &fmt::Arguments, record: &Record) -> String {
+    let mut line = format!(
+        "{} [{}] [{}] {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+        record.level(),
+        record.target(),
+        message
+    );
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    line
+}
+
+// key=value を行末に付け足す。空白などを含む値は区切りが分かるよう引用符で囲む
+struct Fields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        let plain = !value.is_empty()
+            && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=');
+        let result = if plain {
+            write!(self.0, " {}={}", key, value)
+        } else {
+            write!(self.0, " {}={:?}", key, value)
+        };
+        result.map_err(|_| kv::Error::msg("failed to format a log field"))
+    }
+}
+
+/// `dir` にあるログファイルを古い順に返す。ローテーションした `{name}_{日時}.log` の後に
+/// 書き込み中の `{name}.log` が来る。
+pub fn log_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let current = format!("{}.log", LOG_FILE_NAME);
+    let prefix = format!("{}_", LOG_FILE_NAME);
+    let mut rotated = Vec::new();
+    let mut writing = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == current {
+            writing = Some(entry.path());
+        } else if name.starts_with(&prefix) && name.ends_with(".log") {
+            rotated.push((name, entry.path()));
+        }
+    }
+    // 日時は桁を揃えてあるので、名前の順が書かれた順になる
+    rotated.sort();
+    Ok(rotated
+        .into_iter()
+        .map(|(_, path)| path)
+        .chain(writing)
+        .collect())
+}
+
+/// 新しいものから最大 `limit` 行を、古い順に並べて返す。ログファイルがなければ空。
+pub fn recent_lines(dir: &Path, limit: usize) -> io::Result<Vec<String>> {
+    let files = match log_files(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = Vec::new();
+    for path in files.iter().rev() {
+        if lines.len() >= limit {
+            break;
+        }
+        // 書き込み中に消える (ローテーションされる) ことがある
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let text = String::from_utf8_lossy(&bytes);
+        let wanted = limit - lines.len();
+        let mut newest: Vec<String> = text.lines().rev().take(wanted).map(String::from).collect();
+        newest.reverse();
+        // 古いファイルの行を前に足していく
+        newest.append(&mut lines);
+        lines = newest;
+    }
+    Ok(lines)
+}
@@ -0,0 +1,22 @@
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum NormalizationForm {
+    // 合成済みの形。Windows や Linux で一般的
+    #[default]
+    Nfc,
+    // 分解した形。macOS からコピーしたファイル名に多い
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+pub fn normalize(text: &str, form: NormalizationForm) -> String {
+    match form {
+        NormalizationForm::Nfc => text.nfc().collect(),
+        NormalizationForm::Nfd => text.nfd().collect(),
+        NormalizationForm::Nfkc => text.nfkc().collect(),
+        NormalizationForm::Nfkd => text.nfkd().collect(),
+    }
+}
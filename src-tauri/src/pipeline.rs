@@ -0,0 +1,473 @@
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+
+use crate::case::{self, Case, CaseTarget};
+use crate::error::RenameError;
+use crate::normalization::{self, NormalizationForm};
+use crate::rename::{split_extension, SortKey, SortOrder};
+use crate::scan::EntryKind;
+use crate::sequence::{self, SequenceOptions};
+use crate::template::Template;
+use crate::FileEntry;
+
+/// 名前に対する1つの操作。位置と長さは文字数で数える。
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum Operation {
+    // 置換文字列の `{0001}` は一致したエントリの連番になる。
+    // `$1` `$<name>` `$&` などは JavaScript の String.prototype.replace と同じ意味
+    #[serde(rename_all = "camelCase")]
+    RegexReplace {
+        pattern: String,
+        replacement: String,
+        #[serde(default)]
+        all: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    Replace {
+        search: String,
+        replacement: String,
+        #[serde(default)]
+        all: bool,
+    },
+    // from_end なら位置を末尾から数える
+    #[serde(rename_all = "camelCase")]
+    Insert {
+        position: usize,
+        text: String,
+        #[serde(default)]
+        from_end: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    Remove {
+        start: usize,
+        length: usize,
+        #[serde(default)]
+        from_end: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChangeCase {
+        case: Case,
+        #[serde(default)]
+        target: CaseTarget,
+    },
+    // 拡張子も含めて正規化する
+    #[serde(rename_all = "camelCase")]
+    Normalize {
+        #[serde(default)]
+        form: NormalizationForm,
+    },
+    // 空なら前後の空白を除く
+    #[serde(rename_all = "camelCase")]
+    Trim {
+        #[serde(default)]
+        characters: String,
+    },
+    // search が空でなければ一致したエントリだけに適用し、キャプチャを `{$1}` で参照できる
+    #[serde(rename_all = "camelCase")]
+    Template {
+        template: String,
+        #[serde(default)]
+        search: String,
+    },
+}
+
+/// 順に適用する操作の列 (App.vue の入力欄に対応)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RenamePipeline {
+    pub operations: Vec<Operation>,
+    pub preserve_extension: bool,
+    // 連番を振る順序
+    pub sort_key: SortKey,
+    pub sort_order: SortOrder,
+    pub sequence: SequenceOptions,
+}
+
+impl RenamePipeline {
+    /// すべての操作をコンパイルする。失敗した場合は何番目の操作かをエラーに含める。
+    /// 連番を使う操作では、連番の設定も確認する。
+    pub fn compile(&self) -> Result<Vec<Compiled<'_>>, RenameError> {
+        self.operations
+            .iter()
+            .enumerate()
+            .map(|(index, operation)| {
+                Compiled::new(operation)
+                    .and_then(|compiled| {
+                        if compiled.uses_sequence() {
+                            self.sequence.check()?;
+                        }
+                        Ok(compiled)
+                    })
+                    .map_err(|cause| RenameError::InvalidOperation {
+                        index,
+                        cause: Box::new(cause),
+                    })
+            })
+            .collect()
+    }
+
+    /// 操作の対象になる部分と、そのまま残す拡張子に分ける。
+    pub fn split<'a>(&self, name: &'a str, kind: EntryKind) -> (&'a str, &'a str) {
+        // ディレクトリ名には拡張子がない
+        if self.preserve_extension && kind != EntryKind::Directory {
+            split_extension(name)
+        } else {
+            (name, "")
+        }
+    }
+}
+
+pub enum Compiled<'a> {
+    Regex {
+        regex: Regex,
+        replacement: &'a str,
+        all: bool,
+    },
+    Template {
+        template: Template,
+        regex: Option<Regex>,
+    },
+    Other(&'a Operation),
+}
+
+impl<'a> Compiled<'a> {
+    fn new(operation: &'a Operation) -> Result<Self, RenameError> {
+        match operation {
+            Operation::RegexReplace {
+                pattern,
+                replacement,
+                all,
+            } => Ok(Compiled::Regex {
+                regex: Regex::new(pattern)?,
+                replacement,
+                all: *all,
+            }),
+            Operation::Template { template, search } => {
+                let regex = match search.as_str() {
+                    "" => None,
+                    search => Some(Regex::new(search)?),
+                };
+                let template = Template::parse(template)?;
+                template.check_groups(regex.as_ref())?;
+                Ok(Compiled::Template { template, regex })
+            }
+            _ => Ok(Compiled::Other(operation)),
+        }
+    }
+
+    pub fn uses_sequence(&self) -> bool {
+        match self {
+            Compiled::Regex { replacement, .. } => sequence::has_placeholder(replacement),
+            Compiled::Template { template, .. } => template.uses_counter(),
+            Compiled::Other(_) => false,
+        }
+    }
+
+    /// 連番を振る対象か。
+    pub fn selects(&self, name: &str) -> bool {
+        match self {
+            Compiled::Regex { regex, .. } => regex.is_match(name),
+            Compiled::Template { regex, .. } => regex.as_ref().is_none_or(|re| re.is_match(name)),
+            Compiled::Other(_) => false,
+        }
+    }
+
+    /// 現在の名前に操作を適用する。`base` は操作の対象になる部分、`extension` は保持している拡張子。
+    /// `position` は連番の何番目か。
+    pub fn apply(
+        &self,
+        file: &FileEntry,
+        base: &mut String,
+        extension: &mut String,
+        position: Option<u64>,
+        sequence: &SequenceOptions,
+    ) {
+        match self {
+            // 大文字・小文字の変換と正規化は拡張子も対象にできる
+            Compiled::Other(Operation::ChangeCase { case, target }) => {
+                change_case(base, extension, *case, *target, file.kind);
+            }
+            Compiled::Other(Operation::Normalize { form }) => {
+                *base = normalization::normalize(base, *form);
+                *extension = normalization::normalize(extension, *form);
+            }
+            _ => *base = self.apply_to(file, base, position, sequence),
+        }
+    }
+
+    fn apply_to(
+        &self,
+        file: &FileEntry,
+        name: &str,
+        position: Option<u64>,
+        sequence: &SequenceOptions,
+    ) -> String {
+        match self {
+            Compiled::Regex {
+                regex,
+                replacement,
+                all,
+            } => {
+                let replacement = match position {
+                    Some(position) => Cow::Owned(sequence.expand(replacement, position)),
+                    None => Cow::Borrowed(*replacement),
+                };
+                let limit = if *all { 0 } else { 1 };
+                regex
+                    .replacen(name, limit, |caps: &Captures| {
+                        let mut expanded = String::new();
+                        expand_replacement(replacement.as_ref(), regex, caps, name, &mut expanded);
+                        expanded
+                    })
+                    .into_owned()
+            }
+            Compiled::Template { template, regex } => match regex {
+                Some(re) => match re.captures(name) {
+                    Some(caps) => template.render(file, Some(&caps), position, sequence),
+                    None => name.to_string(),
+                },
+                None => template.render(file, None, position, sequence),
+            },
+            Compiled::Other(operation) => apply_simple(operation, name),
+        }
+    }
+}
+
+// JavaScript と同じ規則で置換文字列の `$` を展開する。`$$` は `$`、`$&` は一致した部分、
+// `` $` `` と `$'` はその前後、`$1`〜`$99` と `$<name>` はキャプチャになる。
+// 連番の `{001}` と続けて書けるよう、`${1}` と `${name}` もキャプチャとして扱う。
+// 存在しないグループの番号など、解釈できない `$` はそのまま残す
+fn expand_replacement(
+    replacement: &str,
+    regex: &Regex,
+    caps: &Captures,
+    haystack: &str,
+    dst: &mut String,
+) {
+    let whole = caps.get(0).expect("group 0 always matches");
+    let groups = caps.len() - 1;
+    let group = |index: usize| caps.get(index).map_or("", |m| m.as_str());
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        dst.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let digit = |at: usize| {
+            after
+                .as_bytes()
+                .get(at)
+                .filter(|b| b.is_ascii_digit())
+                .map(|b| usize::from(b - b'0'))
+        };
+        let (text, consumed) = match after.as_bytes().first() {
+            Some(b'$') => ("$", 1),
+            Some(b'&') => (whole.as_str(), 1),
+            Some(b'`') => (&haystack[..whole.start()], 1),
+            Some(b'\'') => (&haystack[whole.end()..], 1),
+            // 名前付きグループがなければ `$<` は文字どおり
+            Some(b'<') if regex.capture_names().flatten().next().is_some() => {
+                match after.find('>') {
+                    Some(end) => (
+                        caps.name(&after[1..end]).map_or("", |m| m.as_str()),
+                        end + 1,
+                    ),
+                    None => ("$", 0),
+                }
+            }
+            Some(b'{') => {
+                let key = after.find('}').map(|end| (&after[1..end], end + 1));
+                match key {
+                    Some((key, consumed)) => match key.parse::<usize>() {
+                        Ok(index) if index <= groups => (group(index), consumed),
+                        Err(_) if regex.capture_names().flatten().any(|name| name == key) => {
+                            (caps.name(key).map_or("", |m| m.as_str()), consumed)
+                        }
+                        _ => ("$", 0),
+                    },
+                    None => ("$", 0),
+                }
+            }
+            // 2桁がグループの数を超えるときは1桁だけを番号とみなす
+            Some(_) => match (digit(0), digit(1)) {
+                (Some(tens), Some(ones)) if (1..=groups).contains(&(tens * 10 + ones)) => {
+                    (group(tens * 10 + ones), 2)
+                }
+                (Some(index), _) if (1..=groups).contains(&index) => (group(index), 1),
+                _ => ("$", 0),
+            },
+            None => ("$", 0),
+        };
+        dst.push_str(text);
+        rest = &after[consumed..];
+    }
+    dst.push_str(rest);
+}
+
+fn apply_simple(operation: &Operation, name: &str) -> String {
+    match operation {
+        Operation::Replace {
+            search,
+            replacement,
+            all,
+        } => {
+            if search.is_empty() {
+                name.to_string()
+            } else if *all {
+                name.replace(search.as_str(), replacement)
+            } else {
+                name.replacen(search.as_str(), replacement, 1)
+            }
+        }
+        Operation::Insert {
+            position,
+            text,
+            from_end,
+        } => {
+            let index = byte_index(name, char_position(name, *position, *from_end));
+            format!("{}{}{}", &name[..index], text, &name[index..])
+        }
+        Operation::Remove {
+            start,
+            length,
+            from_end,
+        } => {
+            // 末尾から数える場合は start が範囲の終わりになる
+            let (start, end) = if *from_end {
+                let end = char_position(name, *start, true);
+                (end.saturating_sub(*length), end)
+            } else {
+                let start = char_position(name, *start, false);
+                (start, start.saturating_add(*length))
+            };
+            let (start, end) = (byte_index(name, start), byte_index(name, end));
+            format!("{}{}", &name[..start], &name[end..])
+        }
+        Operation::Trim { characters } => {
+            if characters.is_empty() {
+                name.trim().to_string()
+            } else {
+                name.trim_matches(|c| characters.contains(c)).to_string()
+            }
+        }
+        Operation::RegexReplace { .. }
+        | Operation::Template { .. }
+        | Operation::ChangeCase { .. }
+        | Operation::Normalize { .. } => {
+            unreachable!("compiled separately")
+        }
+    }
+}
+
+fn change_case(
+    base: &mut String,
+    extension: &mut String,
+    case: Case,
+    target: CaseTarget,
+    kind: EntryKind,
+) {
+    let convert = |stem: &str, extension: &str| {
+        let stem = if target.includes_stem() {
+            case::convert(stem, case)
+        } else {
+            stem.to_string()
+        };
+        let extension = match extension.strip_prefix('.') {
+            Some(rest) if target.includes_extension() => format!(".{}", case::convert(rest, case)),
+            _ => extension.to_string(),
+        };
+        (stem, extension)
+    };
+    if extension.is_empty() {
+        // 拡張子を保持していなければ、操作対象の名前から分ける
+        let (stem, rest) = match kind {
+            EntryKind::Directory => (base.as_str(), ""),
+            _ => split_extension(base),
+        };
+        let (stem, rest) = convert(stem, rest);
+        *base = stem + &rest;
+    } else {
+        let (stem, rest) = convert(base, extension);
+        *base = stem;
+        *extension = rest;
+    }
+}
+
+// 先頭からの位置に直し、名前の範囲に収める
+fn char_position(name: &str, position: usize, from_end: bool) -> usize {
+    let count = name.chars().count();
+    if from_end {
+        count.saturating_sub(position)
+    } else {
+        position.min(count)
+    }
+}
+
+// 文字数で数えた位置をバイト位置にする。範囲外なら末尾
+fn byte_index(name: &str, position: usize) -> usize {
+    name.char_indices()
+        .nth(position)
+        .map_or(name.len(), |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(pattern: &str, name: &str, replacement: &str) -> String {
+        let operation = Operation::RegexReplace {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            all: false,
+        };
+        let compiled = Compiled::new(&operation).unwrap();
+        let file = crate::testing::file_entry(name);
+        compiled.apply_to(&file, name, None, &SequenceOptions::default())
+    }
+
+    #[test]
+    fn replacement_follows_javascript_syntax() {
+        assert_eq!(replace("(\\d+)", "img12", "$1_x"), "img12_x");
+        assert_eq!(replace("\\d+", "img12", "[$&]"), "img[12]");
+        assert_eq!(replace("\\d+", "a1b", "$`|$'"), "aa|bb");
+        assert_eq!(replace("a", "a", "$$1"), "$1");
+        assert_eq!(
+            replace("(?<y>\\d{4})-(\\d+)", "2024-05", "$2.$<y>"),
+            "05.2024"
+        );
+        // $12 はグループが1つなら $1 と "2"
+        assert_eq!(replace("(a)", "a", "$12"), "a2");
+        // 存在しないグループや $0 はそのまま
+        assert_eq!(replace("(a)", "a", "$0$2$<y>$"), "$0$2$<y>$");
+        assert_eq!(replace("(a)(?<b>b)", "ab", "${1}0${b}${3}"), "a0b${3}");
+        // 一致しなかったグループは空
+        assert_eq!(replace("(a)|(b)", "b", "[$1]"), "[]");
+    }
+
+    #[test]
+    fn sequence_width_is_checked_for_operations_that_number() {
+        let pipeline = RenamePipeline {
+            operations: vec![
+                Operation::Trim {
+                    characters: String::new(),
+                },
+                Operation::RegexReplace {
+                    pattern: "^".to_string(),
+                    replacement: "{001}_".to_string(),
+                    all: false,
+                },
+            ],
+            sequence: SequenceOptions {
+                width: Some(4_000_000_000),
+                ..SequenceOptions::default()
+            },
+            ..RenamePipeline::default()
+        };
+        match pipeline.compile() {
+            Err(RenameError::InvalidOperation { index, cause }) => {
+                assert_eq!(index, 1);
+                assert!(matches!(*cause, RenameError::InvalidSequence { .. }));
+            }
+            _ => panic!("the width should be rejected"),
+        }
+    }
+}
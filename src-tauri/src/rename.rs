@@ -0,0 +1,229 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::batch::RenameOptions;
+use crate::casefold::CaseFolding;
+use crate::error::RenameError;
+use crate::pipeline::RenamePipeline;
+use crate::sequence;
+use crate::FileEntry;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    NewName,
+    Path,
+    RelativePath,
+    Kind,
+    Modified,
+    Size,
+    Created,
+    Accessed,
+    Readonly,
+    Mode,
+    Uid,
+    Gid,
+    Inode,
+    Device,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedRename {
+    #[serde(flatten)]
+    pub file: FileEntry,
+    pub error: Option<String>,
+    // 各操作を適用した後の名前
+    pub steps: Vec<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RenamePlan {
+    pub entries: Vec<PlannedRename>,
+}
+
+/// `name` を拡張子の手前で分割する。先頭のドット (`.gitignore` など) は拡張子とみなさない。
+pub fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(index) if index > 0 => name.split_at(index),
+        _ => (name, ""),
+    }
+}
+
+fn compare(a: &FileEntry, b: &FileEntry, key: SortKey) -> Ordering {
+    match key {
+        SortKey::Name => a.name.cmp(&b.name),
+        SortKey::NewName => a.new_name.cmp(&b.new_name),
+        SortKey::Path => a.path.cmp(&b.path),
+        SortKey::RelativePath => a.relative_path.cmp(&b.relative_path),
+        SortKey::Kind => (a.kind as u8).cmp(&(b.kind as u8)),
+        SortKey::Modified => a.modified.cmp(&b.modified),
+        SortKey::Size => a.metadata.size.cmp(&b.metadata.size),
+        SortKey::Created => a.metadata.created.cmp(&b.metadata.created),
+        SortKey::Accessed => a.metadata.accessed.cmp(&b.metadata.accessed),
+        SortKey::Readonly => a.metadata.readonly.cmp(&b.metadata.readonly),
+        SortKey::Mode => a.metadata.mode.cmp(&b.metadata.mode),
+        SortKey::Uid => a.metadata.uid.cmp(&b.metadata.uid),
+        SortKey::Gid => a.metadata.gid.cmp(&b.metadata.gid),
+        SortKey::Inode => a.metadata.inode.cmp(&b.metadata.inode),
+        SortKey::Device => a.metadata.device.cmp(&b.metadata.device),
+    }
+}
+
+pub fn build_plan(
+    mut files: Vec<FileEntry>,
+    pipeline: &RenamePipeline,
+    options: &RenameOptions,
+) -> Result<RenamePlan, RenameError> {
+    let operations = pipeline.compile()?;
+    // 操作の対象になる部分と拡張子
+    let mut names: Vec<(String, String)> = files
+        .iter()
+        .map(|file| {
+            let (base, extension) = pipeline.split(&file.name, file.kind);
+            (base.to_string(), extension.to_string())
+        })
+        .collect();
+    let mut steps: Vec<Vec<String>> = vec![Vec::new(); files.len()];
+    for file in files.iter_mut() {
+        file.new_name = Some(file.name.clone());
+    }
+
+    // 操作ごとにすべてのエントリへ適用する。連番はその時点の名前と順序で振る
+    for operation in &operations {
+        let positions = if operation.uses_sequence() {
+            sequence::positions(
+                &files,
+                |index| operation.selects(&names[index].0),
+                |a, b| match pipeline.sort_order {
+                    SortOrder::Asc => compare(a, b, pipeline.sort_key),
+                    SortOrder::Desc => compare(a, b, pipeline.sort_key).reverse(),
+                },
+                pipeline.sequence.scope,
+            )
+        } else {
+            vec![None; files.len()]
+        };
+        for (index, file) in files.iter_mut().enumerate() {
+            let (base, extension) = &mut names[index];
+            operation.apply(file, base, extension, positions[index], &pipeline.sequence);
+            let new_name = format!("{}{}", base, extension);
+            steps[index].push(new_name.clone());
+            file.new_name = Some(new_name);
+        }
+    }
+
+    // 重複はディレクトリごとに、大文字と小文字を区別するかに合わせて判定する
+    let folding = CaseFolding::for_paths(files.iter().map(|file| file.path.as_path()));
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    for file in &files {
+        if let Some(new_name) = &file.new_name {
+            *counts
+                .entry(folding.key(&file.path.with_file_name(new_name)))
+                .or_default() += 1;
+        }
+    }
+
+    let entries = files
+        .into_iter()
+        .zip(steps)
+        .map(|(file, steps)| {
+            let new_name = file.new_name.clone().unwrap_or_default();
+            let violations = options.validate_name(&new_name);
+            let error = if new_name == file.name {
+                None
+            } else if !violations.is_empty() {
+                Some(
+                    violations
+                        .iter()
+                        .map(|violation| violation.message.as_str())
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                )
+            } else if counts
+                .get(&folding.key(&file.path.with_file_name(&new_name)))
+                .copied()
+                .unwrap_or(0)
+                > 1
+            {
+                Some("Duplicate new name".to_string())
+            } else {
+                None
+            };
+            PlannedRename { file, error, steps }
+        })
+        .collect();
+
+    Ok(RenamePlan { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Operation;
+    use crate::testing::file_entry;
+
+    #[test]
+    fn splits_extension_after_a_leading_dot() {
+        assert_eq!(split_extension("photo.tar.gz"), ("photo.tar", ".gz"));
+        assert_eq!(split_extension(".gitignore"), (".gitignore", ""));
+        assert_eq!(split_extension("README"), ("README", ""));
+    }
+
+    #[test]
+    fn plan_numbers_files_and_flags_duplicates() {
+        let files = vec![
+            file_entry("/photos/b.jpg"),
+            file_entry("/photos/a.jpg"),
+            file_entry("/photos/c.jpg"),
+        ];
+        let pipeline = RenamePipeline {
+            operations: vec![
+                Operation::RegexReplace {
+                    pattern: "^[ab]$".to_string(),
+                    replacement: "img_{01}".to_string(),
+                    all: false,
+                },
+                Operation::Replace {
+                    search: "c".to_string(),
+                    replacement: "img_01".to_string(),
+                    all: false,
+                },
+            ],
+            preserve_extension: true,
+            ..RenamePipeline::default()
+        };
+        let plan = build_plan(files, &pipeline, &RenameOptions::default()).unwrap();
+        let names: Vec<(&str, Option<&str>)> = plan
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.file.new_name.as_deref().unwrap_or_default(),
+                    entry.error.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("img_02.jpg", None),
+                ("img_01.jpg", Some("Duplicate new name")),
+                ("img_01.jpg", Some("Duplicate new name")),
+            ]
+        );
+        assert_eq!(plan.entries[1].steps, ["img_01.jpg", "img_01.jpg"]);
+    }
+}
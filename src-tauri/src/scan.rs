@@ -0,0 +1,655 @@
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+
+use crate::error::RenameError;
+use crate::FileEntry;
+
+// メタデータの取得は入出力待ちが主なので、CPU の数より多くても速くなる
+const DEFAULT_METADATA_THREADS: usize = 8;
+// フロントエンドから大きな値を渡されても、作るスレッドはこの数までにする
+const MAX_METADATA_THREADS: usize = 64;
+// 1つのディレクトリから一度に読み込む件数。この件数ごとにメタデータを取得し、中断を確認する
+const CHUNK_SIZE: usize = 256;
+// これより少ない件数ならスレッドに渡さずに取得する
+const MIN_PARALLEL_ENTRIES: usize = 8;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Directory,
+    Symlink,
+}
+
+// 取得できない項目は None にする
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EntryMetadata {
+    pub size: u64,
+    pub created: Option<DateTime<Utc>>,
+    pub accessed: Option<DateTime<Utc>>,
+    pub readonly: bool,
+    // Unix のパーミッションビット
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub inode: Option<u64>,
+    pub device: Option<u64>,
+}
+
+impl EntryMetadata {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            created: metadata.created().ok().map(DateTime::<Utc>::from),
+            accessed: metadata.accessed().ok().map(DateTime::<Utc>::from),
+            readonly: metadata.permissions().readonly(),
+            ..Self::default()
+        }
+        .with_platform_fields(metadata)
+    }
+
+    #[cfg(unix)]
+    fn with_platform_fields(self, metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            mode: Some(metadata.mode()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            inode: Some(metadata.ino()),
+            device: Some(metadata.dev()),
+            ..self
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn with_platform_fields(self, _metadata: &fs::Metadata) -> Self {
+        self
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScanOptions {
+    // サブディレクトリも読み込む
+    pub recursive: bool,
+    // 何階層下まで読み込むか。None なら制限なし
+    pub max_depth: Option<usize>,
+    // 一覧に含める種類
+    pub kinds: Vec<EntryKind>,
+    // 空なら全件を含める。除外に一致したディレクトリの中は読み込まない
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub pattern_syntax: PatternSyntax,
+    // ドットで始まる名前 (Windows では隠し属性も) を含める
+    pub include_hidden: bool,
+    // 名前が NFC かどうかを確認して FileEntry::is_nfc に記録する
+    pub check_normalization: bool,
+    // メタデータを並行して取得するスレッド数。None なら既定値、1 なら並行しない。
+    // MAX_METADATA_THREADS を超える値は切り詰める
+    pub metadata_threads: Option<usize>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            max_depth: None,
+            kinds: vec![EntryKind::File],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            pattern_syntax: PatternSyntax::Glob,
+            include_hidden: true,
+            check_normalization: false,
+            metadata_threads: None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PatternSyntax {
+    #[default]
+    Glob,
+    Regex,
+}
+
+impl ScanOptions {
+    fn descends_into(&self, depth: usize) -> bool {
+        self.recursive && self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    fn metadata_threads(&self) -> usize {
+        self.metadata_threads
+            .unwrap_or(DEFAULT_METADATA_THREADS)
+            .clamp(1, MAX_METADATA_THREADS)
+    }
+}
+
+// 名前か相対パスのどちらかに一致すれば一致とみなす
+enum Patterns {
+    Glob(GlobSet),
+    Regex(RegexSet),
+}
+
+impl Patterns {
+    fn compile(patterns: &[String], syntax: PatternSyntax) -> Result<Self, RenameError> {
+        match syntax {
+            PatternSyntax::Glob => {
+                let mut builder = GlobSetBuilder::new();
+                for pattern in patterns {
+                    builder.add(Glob::new(pattern).map_err(|e| RenameError::InvalidPattern {
+                        message: e.to_string(),
+                    })?);
+                }
+                let set = builder.build().map_err(|e| RenameError::InvalidPattern {
+                    message: e.to_string(),
+                })?;
+                Ok(Patterns::Glob(set))
+            }
+            PatternSyntax::Regex => Ok(Patterns::Regex(RegexSet::new(patterns)?)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Patterns::Glob(set) => set.is_empty(),
+            Patterns::Regex(set) => set.is_empty(),
+        }
+    }
+
+    fn matches(&self, name: &str, relative_path: &Path) -> bool {
+        match self {
+            Patterns::Glob(set) => set.is_match(name) || set.is_match(relative_path),
+            Patterns::Regex(set) => {
+                set.is_match(name) || set.is_match(&relative_path.to_string_lossy())
+            }
+        }
+    }
+}
+
+struct Filter {
+    include: Patterns,
+    exclude: Patterns,
+    include_hidden: bool,
+}
+
+impl Filter {
+    fn new(options: &ScanOptions) -> Result<Self, RenameError> {
+        Ok(Self {
+            include: Patterns::compile(&options.include, options.pattern_syntax)?,
+            exclude: Patterns::compile(&options.exclude, options.pattern_syntax)?,
+            include_hidden: options.include_hidden,
+        })
+    }
+
+    // 除外されたエントリは一覧にも含めず、ディレクトリなら中も読まない
+    fn excludes(&self, entry: &fs::DirEntry, name: &str, relative_path: &Path) -> bool {
+        (!self.include_hidden && is_hidden(entry, name))
+            || self.exclude.matches(name, relative_path)
+    }
+
+    fn includes(&self, name: &str, relative_path: &Path) -> bool {
+        self.include.is_empty() || self.include.matches(name, relative_path)
+    }
+}
+
+#[cfg(windows)]
+fn is_hidden(entry: &fs::DirEntry, name: &str) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    name.starts_with('.')
+        || entry
+            .metadata()
+            .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn is_hidden(_entry: &fs::DirEntry, name: &str) -> bool {
+    name.starts_with('.')
+}
+
+// 読み込めなかったエントリ。一覧の他のエントリには影響しない
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanWarning {
+    pub path: PathBuf,
+    pub error: RenameError,
+}
+
+#[derive(serde::Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanResult {
+    pub entries: Vec<FileEntry>,
+    pub warnings: Vec<ScanWarning>,
+}
+
+// 分割して送った場合の件数
+#[derive(serde::Serialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanSummary {
+    pub entries: usize,
+    pub warnings: usize,
+    // 途中で読み込みをやめた
+    pub stopped: bool,
+}
+
+// 読み込んだエントリと警告の受け取り先。Break を返すと読み込みをやめる
+trait Sink {
+    fn entry(&mut self, entry: FileEntry) -> ControlFlow<()>;
+    fn warning(&mut self, warning: ScanWarning) -> ControlFlow<()>;
+
+    fn warn(&mut self, path: &Path, error: &io::Error) -> ControlFlow<()> {
+        self.warning(ScanWarning {
+            path: path.to_path_buf(),
+            error: RenameError::io(path, error),
+        })
+    }
+
+    // 一覧に含めるエントリがなくても、読み込みの途中で確認する
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+impl Sink for ScanResult {
+    fn entry(&mut self, entry: FileEntry) -> ControlFlow<()> {
+        self.entries.push(entry);
+        ControlFlow::Continue(())
+    }
+
+    fn warning(&mut self, warning: ScanWarning) -> ControlFlow<()> {
+        self.warnings.push(warning);
+        ControlFlow::Continue(())
+    }
+}
+
+// 一定の件数ごとに send へ渡す
+struct Batches<'a, F> {
+    batch: ScanResult,
+    size: usize,
+    send: F,
+    cancelled: &'a AtomicBool,
+    summary: ScanSummary,
+}
+
+impl<F: FnMut(ScanResult) -> ControlFlow<()>> Batches<'_, F> {
+    fn flush(&mut self) -> ControlFlow<()> {
+        if self.batch.entries.is_empty() && self.batch.warnings.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        (self.send)(std::mem::take(&mut self.batch))
+    }
+
+    fn flush_if_full(&mut self) -> ControlFlow<()> {
+        if self.batch.entries.len() + self.batch.warnings.len() >= self.size {
+            self.flush()
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+impl<F: FnMut(ScanResult) -> ControlFlow<()>> Sink for Batches<'_, F> {
+    fn entry(&mut self, entry: FileEntry) -> ControlFlow<()> {
+        self.summary.entries += 1;
+        self.batch.entries.push(entry);
+        self.flush_if_full()
+    }
+
+    fn warning(&mut self, warning: ScanWarning) -> ControlFlow<()> {
+        self.summary.warnings += 1;
+        self.batch.warnings.push(warning);
+        self.flush_if_full()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// `root` 以下のエントリを一覧にする。`relative_path` は `root` からの相対パス。
+///
+/// `root` 自体が読めない場合のみエラーを返し、個々のエントリやサブディレクトリの
+/// 失敗は警告として結果に含める。
+pub fn scan_directory(root: &Path, options: &ScanOptions) -> Result<ScanResult, RenameError> {
+    let filter = Filter::new(options)?;
+    let read_dir = fs::read_dir(root).map_err(|e| RenameError::io(root, &e))?;
+    let mut result = ScanResult::default();
+    let _ = with_workers(options, |workers| {
+        scan_into(
+            root,
+            root,
+            read_dir,
+            0,
+            options,
+            &filter,
+            workers,
+            &mut result,
+        )
+    });
+    Ok(result)
+}
+
+/// `scan_directory` と同じ順に読み込み、エントリと警告を合わせて `batch_size` 件ずつ `send` へ渡す。
+///
+/// `send` が `ControlFlow::Break` を返すか `cancelled` が真になると、残りを読まずに終える。
+/// `cancelled` は送るものがなくても、一定の件数を読むごとに確認する。
+pub fn scan_directory_batches(
+    root: &Path,
+    options: &ScanOptions,
+    batch_size: usize,
+    cancelled: &AtomicBool,
+    send: impl FnMut(ScanResult) -> ControlFlow<()>,
+) -> Result<ScanSummary, RenameError> {
+    let filter = Filter::new(options)?;
+    let read_dir = fs::read_dir(root).map_err(|e| RenameError::io(root, &e))?;
+    let mut batches = Batches {
+        batch: ScanResult::default(),
+        size: batch_size.max(1),
+        send,
+        cancelled,
+        summary: ScanSummary::default(),
+    };
+    let flow = with_workers(options, |workers| {
+        scan_into(
+            root,
+            root,
+            read_dir,
+            0,
+            options,
+            &filter,
+            workers,
+            &mut batches,
+        )
+    });
+    batches.summary.stopped = flow.is_break();
+    if flow.is_continue() {
+        let _ = batches.flush();
+    }
+    Ok(batches.summary)
+}
+
+// ディレクトリ内の1件。メタデータはまだ取得していない
+struct Candidate {
+    entry: fs::DirEntry,
+    path: PathBuf,
+    name: String,
+    relative_path: PathBuf,
+    kind: EntryKind,
+    // 一覧に含める
+    listed: bool,
+}
+
+impl Candidate {
+    fn read(&self, options: &ScanOptions) -> Option<io::Result<FileEntry>> {
+        self.listed.then(|| {
+            read_entry(
+                &self.entry,
+                &self.name,
+                &self.relative_path,
+                self.kind,
+                options,
+            )
+        })
+    }
+}
+
+type Fetched = (Candidate, Option<io::Result<FileEntry>>);
+
+fn fetch_all(candidates: Vec<Candidate>, options: &ScanOptions) -> Vec<Fetched> {
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let entry = candidate.read(options);
+            (candidate, entry)
+        })
+        .collect()
+}
+
+// メタデータを取得するスレッド。読み込み全体で使い回す
+struct Workers {
+    threads: usize,
+    jobs: mpsc::Sender<(usize, Vec<Candidate>)>,
+    done: mpsc::Receiver<(usize, Vec<Fetched>)>,
+}
+
+impl Workers {
+    /// スレッドの数に分けてメタデータを取得し、渡された順に返す。
+    fn fetch(&self, candidates: Vec<Candidate>) -> Vec<Fetched> {
+        let size = candidates.len().div_ceil(self.threads);
+        let mut candidates = candidates.into_iter();
+        let mut pieces = 0;
+        loop {
+            let piece: Vec<Candidate> = candidates.by_ref().take(size).collect();
+            if piece.is_empty() {
+                break;
+            }
+            self.jobs
+                .send((pieces, piece))
+                .expect("metadata workers stopped");
+            pieces += 1;
+        }
+        let mut fetched: Vec<Vec<Fetched>> = (0..pieces).map(|_| Vec::new()).collect();
+        for _ in 0..pieces {
+            let (index, piece) = self.done.recv().expect("metadata workers stopped");
+            fetched[index] = piece;
+        }
+        fetched.into_iter().flatten().collect()
+    }
+}
+
+// options.metadata_threads 個のスレッドを用意して f を実行する。1 ならスレッドを使わない
+fn with_workers<R>(options: &ScanOptions, f: impl FnOnce(Option<&Workers>) -> R) -> R {
+    let threads = options.metadata_threads();
+    if threads <= 1 {
+        return f(None);
+    }
+    let (jobs, receiver) = mpsc::channel::<(usize, Vec<Candidate>)>();
+    let receiver = Mutex::new(receiver);
+    let (sender, done) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let receiver = &receiver;
+            let sender = sender.clone();
+            scope.spawn(move || loop {
+                let job = receiver
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+                // jobs が破棄されたら終わる
+                let Ok((index, piece)) = job else {
+                    break;
+                };
+                if sender.send((index, fetch_all(piece, options))).is_err() {
+                    break;
+                }
+            });
+        }
+        // f が終わると jobs が破棄され、スレッドも終わる
+        drop(sender);
+        f(Some(&Workers {
+            threads,
+            jobs,
+            done,
+        }))
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn scan_into(
+    root: &Path,
+    dir: &Path,
+    mut read_dir: fs::ReadDir,
+    depth: usize,
+    options: &ScanOptions,
+    filter: &Filter,
+    workers: Option<&Workers>,
+    sink: &mut dyn Sink,
+) -> ControlFlow<()> {
+    // 大きなディレクトリでも少しずつ結果を渡せるよう、一定の件数ごとに処理する
+    loop {
+        if sink.is_cancelled() {
+            return ControlFlow::Break(());
+        }
+        let mut candidates = Vec::with_capacity(CHUNK_SIZE);
+        let mut read = 0;
+        for entry_result in read_dir.by_ref().take(CHUNK_SIZE) {
+            read += 1;
+            let entry = match entry_result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    sink.warn(dir, &e)?;
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative_path = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            if filter.excludes(&entry, &name, &relative_path) {
+                continue;
+            }
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    sink.warn(&path, &e)?;
+                    continue;
+                }
+            };
+
+            // シンボリックリンクは辿らない
+            let kind = if file_type.is_symlink() {
+                EntryKind::Symlink
+            } else if file_type.is_dir() {
+                EntryKind::Directory
+            } else if file_type.is_file() {
+                EntryKind::File
+            } else {
+                continue;
+            };
+
+            let listed = options.kinds.contains(&kind) && filter.includes(&name, &relative_path);
+            candidates.push(Candidate {
+                entry,
+                path,
+                name,
+                relative_path,
+                kind,
+                listed,
+            });
+        }
+        if read == 0 {
+            return ControlFlow::Continue(());
+        }
+
+        // 時間のかかるメタデータの取得だけを並行して行い、結果は読み込んだ順に渡す
+        let fetched = match workers {
+            Some(workers) if candidates.len() >= MIN_PARALLEL_ENTRIES => workers.fetch(candidates),
+            _ => fetch_all(candidates, options),
+        };
+        for (candidate, entry) in fetched {
+            match entry {
+                Some(Ok(file_entry)) => sink.entry(file_entry)?,
+                Some(Err(e)) => sink.warn(&candidate.path, &e)?,
+                None => {}
+            }
+
+            if candidate.kind == EntryKind::Directory && options.descends_into(depth) {
+                match fs::read_dir(&candidate.path) {
+                    Ok(read_dir) => scan_into(
+                        root,
+                        &candidate.path,
+                        read_dir,
+                        depth + 1,
+                        options,
+                        filter,
+                        workers,
+                        sink,
+                    )?,
+                    Err(e) => sink.warn(&candidate.path, &e)?,
+                }
+            }
+        }
+    }
+}
+
+fn read_entry(
+    entry: &fs::DirEntry,
+    name: &str,
+    relative_path: &Path,
+    kind: EntryKind,
+    options: &ScanOptions,
+) -> io::Result<FileEntry> {
+    // DirEntry::metadata はシンボリックリンク自体の情報を返す
+    let metadata = entry.metadata()?;
+    let modified: DateTime<Utc> = metadata.modified()?.into();
+    let is_nfc = options
+        .check_normalization
+        .then(|| unicode_normalization::is_nfc(name));
+    Ok(FileEntry {
+        name: name.to_string(),
+        relative_path: relative_path.to_path_buf(),
+        path: entry.path(),
+        kind,
+        modified,
+        metadata: EntryMetadata::from_metadata(&metadata),
+        is_nfc,
+        new_name: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn metadata_threads_are_bounded() {
+        let threads = |metadata_threads| {
+            ScanOptions {
+                metadata_threads,
+                ..ScanOptions::default()
+            }
+            .metadata_threads()
+        };
+        assert_eq!(threads(None), DEFAULT_METADATA_THREADS);
+        assert_eq!(threads(Some(0)), 1);
+        assert_eq!(threads(Some(usize::MAX)), MAX_METADATA_THREADS);
+    }
+
+    #[test]
+    fn cancellation_is_checked_when_nothing_is_listed() {
+        let dir = TempDir::new("scan-cancel");
+        for index in 0..CHUNK_SIZE * 2 {
+            dir.write(&format!("{}.tmp", index), "");
+        }
+        // 一覧に含めるエントリがないので send は呼ばれない
+        let options = ScanOptions {
+            exclude: vec!["*.tmp".to_string()],
+            ..ScanOptions::default()
+        };
+        let cancelled = AtomicBool::new(true);
+        let summary = scan_directory_batches(dir.root(), &options, 10, &cancelled, |_| {
+            panic!("nothing should be sent")
+        })
+        .unwrap();
+        assert!(summary.stopped);
+
+        let summary =
+            scan_directory_batches(dir.root(), &options, 10, &AtomicBool::new(false), |_| {
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert!(!summary.stopped);
+        assert_eq!(summary.entries, 0);
+    }
+}
@@ -0,0 +1,183 @@
+use regex::{Captures, Regex};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::error::RenameError;
+use crate::validation::MAX_NAME_BYTES;
+use crate::FileEntry;
+
+// 置換文字列中の `{0001}` 形式の連番プレースホルダ。`${1}` はキャプチャの参照なので対象外
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\$?)\{(\d+)\}").expect("valid sequence pattern"));
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum SequenceScope {
+    // 全体で1つの連番
+    #[default]
+    Global,
+    // ディレクトリごとに振り直す
+    Directory,
+}
+
+// 連番の振り方。未指定の開始番号と桁数はプレースホルダの数字から決める
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SequenceOptions {
+    pub start: Option<u64>,
+    pub step: u64,
+    pub width: Option<usize>,
+    pub scope: SequenceScope,
+}
+
+impl Default for SequenceOptions {
+    fn default() -> Self {
+        Self {
+            start: None,
+            step: 1,
+            width: None,
+            scope: SequenceScope::Global,
+        }
+    }
+}
+
+impl SequenceOptions {
+    /// 桁数が名前に収まるか確認する。大きすぎる桁数は、0 で埋めるだけで大量のメモリを使う。
+    pub fn check(&self) -> Result<(), RenameError> {
+        match self.width {
+            Some(width) if width > MAX_NAME_BYTES => Err(RenameError::InvalidSequence {
+                message: format!("{} digits is too many (limit {})", width, MAX_NAME_BYTES),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// `position` 番目の番号。開始番号の指定がなければ `default_start` から数える。
+    pub fn number(&self, position: u64, default_start: u64) -> u64 {
+        self.start
+            .unwrap_or(default_start)
+            .saturating_add(self.step.saturating_mul(position))
+    }
+
+    /// `replace` 中のすべてのプレースホルダを `position` 番目の番号に置き換える。
+    pub fn expand(&self, replace: &str, position: u64) -> String {
+        PLACEHOLDER
+            .replace_all(replace, |caps: &Captures| {
+                if !caps[1].is_empty() {
+                    return caps[0].to_string();
+                }
+                let digits = &caps[2];
+                let value = self.number(position, digits.parse().unwrap_or(0));
+                let width = self.width.unwrap_or(digits.len());
+                format!("{:0width$}", value)
+            })
+            .into_owned()
+    }
+}
+
+pub fn has_placeholder(replace: &str) -> bool {
+    PLACEHOLDER
+        .captures_iter(replace)
+        .any(|caps| caps[1].is_empty())
+}
+
+/// `selected` が真になる位置のエントリに `compare` の順で 0 から番号を振る。選ばれなかったエントリは `None`。
+///
+/// 同順位はパスで並べるので、入力の順序によらず同じ結果になる。
+pub fn positions(
+    files: &[FileEntry],
+    selected: impl Fn(usize) -> bool,
+    compare: impl Fn(&FileEntry, &FileEntry) -> Ordering,
+    scope: SequenceScope,
+) -> Vec<Option<u64>> {
+    let mut order: Vec<usize> = (0..files.len()).filter(|&i| selected(i)).collect();
+    order.sort_by(|&a, &b| {
+        compare(&files[a], &files[b]).then_with(|| files[a].path.cmp(&files[b].path))
+    });
+
+    let mut counters: HashMap<Option<&Path>, u64> = HashMap::new();
+    let mut positions = vec![None; files.len()];
+    for index in order {
+        let group = match scope {
+            SequenceScope::Global => None,
+            SequenceScope::Directory => files[index].path.parent(),
+        };
+        let counter = counters.entry(group).or_default();
+        positions[index] = Some(*counter);
+        *counter += 1;
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::file_entry;
+
+    fn by_name(a: &FileEntry, b: &FileEntry) -> Ordering {
+        a.name.cmp(&b.name)
+    }
+
+    #[test]
+    fn positions_follow_the_sort_order() {
+        let files = [
+            file_entry("/a/3.jpg"),
+            file_entry("/a/1.jpg"),
+            file_entry("/b/2.jpg"),
+            file_entry("/b/skip.txt"),
+        ];
+        let selected = |index: usize| files[index].name.ends_with(".jpg");
+        assert_eq!(
+            positions(&files, selected, by_name, SequenceScope::Global),
+            [Some(2), Some(0), Some(1), None]
+        );
+        assert_eq!(
+            positions(&files, selected, by_name, SequenceScope::Directory),
+            [Some(1), Some(0), Some(0), None]
+        );
+    }
+
+    #[test]
+    fn ties_are_ordered_by_path() {
+        let files = [file_entry("/b/x.jpg"), file_entry("/a/x.jpg")];
+        let forward = positions(&files, |_| true, by_name, SequenceScope::Global);
+        let reversed: Vec<FileEntry> = files.iter().rev().cloned().collect();
+        let backward = positions(&reversed, |_| true, by_name, SequenceScope::Global);
+        assert_eq!(forward, [Some(1), Some(0)]);
+        assert_eq!(backward, [Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let options = SequenceOptions::default();
+        assert_eq!(options.expand("IMG_{001}", 0), "IMG_001");
+        assert_eq!(options.expand("IMG_{001}", 9), "IMG_010");
+        // `${1}` はキャプチャの参照なのでそのまま残す
+        assert_eq!(options.expand("${1}_{0}", 3), "${1}_3");
+
+        let options = SequenceOptions {
+            start: Some(5),
+            step: 2,
+            width: Some(4),
+            ..SequenceOptions::default()
+        };
+        assert_eq!(options.expand("{1}-{1}", 1), "0007-0007");
+        assert!(has_placeholder("a{01}"));
+        assert!(!has_placeholder("a${1}"));
+    }
+
+    #[test]
+    fn rejects_width_longer_than_a_name() {
+        let options = |width| SequenceOptions {
+            width: Some(width),
+            ..SequenceOptions::default()
+        };
+        assert!(options(MAX_NAME_BYTES).check().is_ok());
+        assert!(matches!(
+            options(4_000_000_000).check(),
+            Err(RenameError::InvalidSequence { .. })
+        ));
+    }
+}
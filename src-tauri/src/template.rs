@@ -0,0 +1,322 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use regex::{Captures, Regex};
+
+use crate::rename::split_extension;
+use crate::scan::EntryKind;
+use crate::sequence::SequenceOptions;
+use crate::validation::MAX_NAME_BYTES;
+use crate::FileEntry;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d";
+
+/// テンプレートの構文エラー。`position` は先頭からの文字数 (0 始まり)。
+#[derive(Debug)]
+pub struct TemplateError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl TemplateError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        Self {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+enum Group {
+    Index(usize),
+    Name(String),
+}
+
+enum Segment {
+    Literal(String),
+    // 拡張子を除いた元の名前
+    Stem,
+    // ドットを含む拡張子。`{name}{ext}` で元の名前になる
+    Extension,
+    // 親ディレクトリの名前
+    Parent,
+    // 検索パターンのキャプチャ
+    Group { group: Group, position: usize },
+    // 桁数に満たない分は 0 で埋める
+    Counter { width: Option<usize> },
+    // 更新日時をローカル時刻で書式化する
+    Modified(String),
+}
+
+/// `{name}`, `{ext}`, `{parent}`, `{$1}`, `{n:03}`, `{mtime:%Y%m%d}` を含む新しい名前のテンプレート。
+/// `{{` と `}}` はそれぞれ `{` と `}` になる。
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let chars: Vec<char> = source.chars().collect();
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '{' if chars.get(i + 1) == Some(&'{') => {
+                    literal.push('{');
+                    i += 2;
+                }
+                '}' if chars.get(i + 1) == Some(&'}') => {
+                    literal.push('}');
+                    i += 2;
+                }
+                '}' => return Err(TemplateError::new(i, "Unmatched '}'")),
+                '{' => {
+                    let end = chars[i + 1..]
+                        .iter()
+                        .position(|&c| c == '}')
+                        .map(|offset| i + 1 + offset)
+                        .ok_or_else(|| TemplateError::new(i, "Unclosed '{'"))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(parse_token(&chars[i + 1..end], i + 1)?);
+                    i = end + 1;
+                }
+                c => {
+                    literal.push(c);
+                    i += 1;
+                }
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn uses_counter(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Counter { .. }))
+    }
+
+    /// キャプチャの参照が検索パターンに存在するか確認する。
+    pub fn check_groups(&self, regex: Option<&Regex>) -> Result<(), TemplateError> {
+        for segment in &self.segments {
+            let Segment::Group { group, position } = segment else {
+                continue;
+            };
+            let Some(regex) = regex else {
+                return Err(TemplateError::new(
+                    *position,
+                    "Capture groups require a search pattern",
+                ));
+            };
+            match group {
+                Group::Index(index) if *index >= regex.captures_len() => {
+                    return Err(TemplateError::new(
+                        *position,
+                        format!("No capture group {}", index),
+                    ));
+                }
+                Group::Name(name) if !regex.capture_names().any(|n| n == Some(name)) => {
+                    return Err(TemplateError::new(
+                        *position,
+                        format!("No capture group named '{}'", name),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// `file` に対してテンプレートを展開する。`position` が `None` なら連番は空になる。
+    pub fn render(
+        &self,
+        file: &FileEntry,
+        captures: Option<&Captures>,
+        position: Option<u64>,
+        sequence: &SequenceOptions,
+    ) -> String {
+        // ディレクトリ名には拡張子がない
+        let (stem, extension) = if file.kind == EntryKind::Directory {
+            (file.name.as_str(), "")
+        } else {
+            split_extension(&file.name)
+        };
+        let mut result = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => result.push_str(text),
+                Segment::Stem => result.push_str(stem),
+                Segment::Extension => result.push_str(extension),
+                Segment::Parent => {
+                    if let Some(parent) = file.path.parent().and_then(|p| p.file_name()) {
+                        result.push_str(&parent.to_string_lossy());
+                    }
+                }
+                Segment::Group { group, .. } => {
+                    let matched = captures.and_then(|caps| match group {
+                        Group::Index(index) => caps.get(*index),
+                        Group::Name(name) => caps.name(name),
+                    });
+                    if let Some(matched) = matched {
+                        result.push_str(matched.as_str());
+                    }
+                }
+                Segment::Counter { width } => {
+                    if let Some(position) = position {
+                        let value = sequence.number(position, 1);
+                        let width = width.or(sequence.width).unwrap_or(0);
+                        result.push_str(&format!("{:0width$}", value));
+                    }
+                }
+                Segment::Modified(format) => {
+                    let modified = file.modified.with_timezone(&Local);
+                    result.push_str(&modified.format(format).to_string());
+                }
+            }
+        }
+        result
+    }
+}
+
+// `start` は `{` の直後の位置
+fn parse_token(token: &[char], start: usize) -> Result<Segment, TemplateError> {
+    let token: String = token.iter().collect();
+    let (key, spec) = match token.split_once(':') {
+        Some((key, spec)) => (key, Some(spec)),
+        None => (token.as_str(), None),
+    };
+    // 書式の開始位置
+    let spec_start = start + key.chars().count() + 1;
+    let no_spec = |segment: Segment| match spec {
+        Some(_) => Err(TemplateError::new(
+            spec_start,
+            format!("'{}' does not take a format", key),
+        )),
+        None => Ok(segment),
+    };
+
+    match key {
+        "" => Err(TemplateError::new(start, "Empty token")),
+        "name" => no_spec(Segment::Stem),
+        "ext" => no_spec(Segment::Extension),
+        "parent" => no_spec(Segment::Parent),
+        "n" => match spec {
+            None => Ok(Segment::Counter { width: None }),
+            Some(spec) => {
+                let width: usize = spec.parse().map_err(|_| {
+                    TemplateError::new(spec_start, format!("Invalid counter width '{}'", spec))
+                })?;
+                // 名前に収まらない桁数は、0 で埋めるだけで大量のメモリを使う
+                if width > MAX_NAME_BYTES {
+                    return Err(TemplateError::new(
+                        spec_start,
+                        format!(
+                            "Counter width {} is too large (limit {})",
+                            width, MAX_NAME_BYTES
+                        ),
+                    ));
+                }
+                Ok(Segment::Counter { width: Some(width) })
+            }
+        },
+        "mtime" => {
+            let format = spec.unwrap_or(DEFAULT_DATE_FORMAT);
+            if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(TemplateError::new(
+                    spec_start,
+                    format!("Invalid date format '{}'", format),
+                ));
+            }
+            Ok(Segment::Modified(format.to_string()))
+        }
+        _ => match key.strip_prefix('$') {
+            Some("") => Err(TemplateError::new(start, "Missing capture group")),
+            Some(group) => {
+                let group = match group.parse() {
+                    Ok(index) => Group::Index(index),
+                    Err(_) => Group::Name(group.to_string()),
+                };
+                no_spec(Segment::Group {
+                    group,
+                    position: start,
+                })
+            }
+            None => Err(TemplateError::new(
+                start,
+                format!("Unknown token '{}'", key),
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::file_entry;
+
+    fn error_position(source: &str) -> usize {
+        match Template::parse(source) {
+            Ok(_) => panic!("'{}' should be rejected", source),
+            Err(error) => error.position,
+        }
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        for (source, position) in [
+            ("{name", 0),
+            ("a}", 1),
+            ("x{}", 2),
+            ("{foo}", 1),
+            ("{name:x}", 6),
+            ("{n:abc}", 3),
+            ("{mtime:}", 7),
+            ("{mtime:%Q}", 7),
+            ("{$}", 1),
+            ("ab{{c}}{nope}", 8),
+            // 位置はバイトではなく文字で数える
+            ("写真_{foo}", 4),
+        ] {
+            assert_eq!(error_position(source), position, "{}", source);
+        }
+    }
+
+    #[test]
+    fn checks_capture_groups_against_the_pattern() {
+        let template = Template::parse("x_{$2}").unwrap();
+        let regex = Regex::new("(a)").unwrap();
+        assert_eq!(template.check_groups(Some(&regex)).unwrap_err().position, 3);
+        assert_eq!(template.check_groups(None).unwrap_err().position, 3);
+        let named = Template::parse("{$year}").unwrap();
+        assert!(named
+            .check_groups(Some(&Regex::new("(?<year>\\d{4})").unwrap()))
+            .is_ok());
+    }
+
+    #[test]
+    fn renders_name_extension_and_counter() {
+        let file = file_entry("/photos/trip/IMG 1.jpg");
+        let template = Template::parse("{parent}_{name}_{n:03}{ext} {{x}}").unwrap();
+        let sequence = SequenceOptions::default();
+        assert_eq!(
+            template.render(&file, None, Some(4), &sequence),
+            "trip_IMG 1_005.jpg {x}"
+        );
+        // 連番を振らないエントリでは空になる
+        assert_eq!(
+            template.render(&file, None, None, &sequence),
+            "trip_IMG 1_.jpg {x}"
+        );
+    }
+
+    #[test]
+    fn rejects_huge_counter_width() {
+        assert_eq!(error_position("a_{n:4000000000}"), 5);
+        assert_eq!(error_position("{n:256}"), 3);
+        assert!(Template::parse("{n:255}").is_ok());
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::scan::EntryKind;
+use crate::FileEntry;
+
+/// テストごとの空の一時ディレクトリ。破棄すると中身ごと削除する。
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rename-app-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(fs::canonicalize(&dir).unwrap())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.0.join(relative)
+    }
+
+    /// 親ディレクトリも作って `relative` に `contents` を書き込む。
+    pub fn write(&self, relative: &str, contents: &str) -> PathBuf {
+        let path = self.path(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    pub fn read(&self, relative: &str) -> String {
+        fs::read_to_string(self.path(relative)).unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `path` にあるファイルのエントリ。メタデータは既定値にする。
+pub fn file_entry(path: &str) -> FileEntry {
+    let path = PathBuf::from(path);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    FileEntry {
+        relative_path: PathBuf::from(&name),
+        name,
+        path,
+        kind: EntryKind::File,
+        modified: Default::default(),
+        metadata: Default::default(),
+        is_nfc: None,
+        new_name: None,
+    }
+}
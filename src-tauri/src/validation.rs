@@ -0,0 +1,370 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::RenameError;
+
+// Linux の NAME_MAX (UTF-8 のバイト数) と Windows の上限 (UTF-16 の単位数)
+pub const MAX_NAME_BYTES: usize = 255;
+const MAX_NAME_UTF16_UNITS: usize = 255;
+
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// どの環境で使える名前として検証するか。`Portable` は Linux と Windows の両方の制限を課す。
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum TargetPlatform {
+    Linux,
+    Windows,
+    Portable,
+}
+
+// 既定は実行中の OS
+impl Default for TargetPlatform {
+    fn default() -> Self {
+        if cfg!(windows) {
+            TargetPlatform::Windows
+        } else {
+            TargetPlatform::Linux
+        }
+    }
+}
+
+impl TargetPlatform {
+    fn windows_rules(self) -> bool {
+        self != TargetPlatform::Linux
+    }
+
+    fn linux_rules(self) -> bool {
+        self != TargetPlatform::Windows
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ViolationKind {
+    Empty,
+    ReservedName,
+    PathSeparator,
+    Nul,
+    ControlCharacter,
+    ForbiddenCharacter,
+    TrailingDotOrSpace,
+    TooLong,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(kind: ViolationKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+// 1件分の検証結果。index は rename_files に渡された順序
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NameViolations {
+    pub index: usize,
+    pub path: PathBuf,
+    pub new_name: String,
+    pub violations: Vec<Violation>,
+}
+
+/// 新しい名前として使えるかどうかを確認し、違反をすべて返す。
+pub fn validate_name(name: &str, platform: TargetPlatform) -> Vec<Violation> {
+    if name.trim().is_empty() {
+        return vec![Violation::new(ViolationKind::Empty, "New name is empty")];
+    }
+
+    let mut violations = Vec::new();
+    if name == "." || name == ".." {
+        violations.push(Violation::new(
+            ViolationKind::ReservedName,
+            format!("'{}' is a reserved name", name),
+        ));
+    }
+
+    let separators: &[char] = if platform.windows_rules() {
+        &['/', '\\']
+    } else {
+        &['/']
+    };
+    if name.contains(separators) {
+        violations.push(Violation::new(
+            ViolationKind::PathSeparator,
+            "New name contains a path separator",
+        ));
+    }
+    if name.contains('\0') {
+        violations.push(Violation::new(
+            ViolationKind::Nul,
+            "New name contains a NUL character",
+        ));
+    }
+
+    if platform.windows_rules() {
+        if name.chars().any(|c| c != '\0' && c.is_ascii_control()) {
+            violations.push(Violation::new(
+                ViolationKind::ControlCharacter,
+                "New name contains a control character",
+            ));
+        }
+        let forbidden: String = WINDOWS_FORBIDDEN
+            .iter()
+            .filter(|&&c| name.contains(c))
+            .collect();
+        if !forbidden.is_empty() {
+            violations.push(Violation::new(
+                ViolationKind::ForbiddenCharacter,
+                format!(
+                    "New name contains characters not allowed on Windows: {}",
+                    forbidden
+                ),
+            ));
+        }
+        if name.ends_with(['.', ' ']) {
+            violations.push(Violation::new(
+                ViolationKind::TrailingDotOrSpace,
+                "New name ends with a dot or space",
+            ));
+        }
+        // `CON.txt` のように拡張子が付いていても予約されている
+        let device = name.split('.').next().unwrap_or(name).trim_end();
+        if WINDOWS_RESERVED
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(device))
+        {
+            violations.push(Violation::new(
+                ViolationKind::ReservedName,
+                format!("'{}' is a reserved name on Windows", device),
+            ));
+        }
+    }
+
+    if platform.linux_rules() && name.len() > MAX_NAME_BYTES {
+        violations.push(Violation::new(
+            ViolationKind::TooLong,
+            format!(
+                "New name is {} bytes long (limit {})",
+                name.len(),
+                MAX_NAME_BYTES
+            ),
+        ));
+    } else if platform.windows_rules() && name.encode_utf16().count() > MAX_NAME_UTF16_UNITS {
+        violations.push(Violation::new(
+            ViolationKind::TooLong,
+            format!(
+                "New name is {} UTF-16 units long (limit {})",
+                name.encode_utf16().count(),
+                MAX_NAME_UTF16_UNITS
+            ),
+        ));
+    }
+
+    violations
+}
+
+/// サブフォルダへの移動を許す場合の検証。`/` で区切った各部分を名前として検証する。
+pub fn validate_relative_path(path: &str, platform: TargetPlatform) -> Vec<Violation> {
+    if path.trim().is_empty() {
+        return vec![Violation::new(ViolationKind::Empty, "New name is empty")];
+    }
+    // `2024/` のように最後が区切り文字だと、名前の部分が空になる
+    path.split('/')
+        .flat_map(|component| validate_name(component, platform))
+        .collect()
+}
+
+/// `root` の中にある `path` を `new_name` に変えた移動先を求める。
+///
+/// `path` が `root` の外にある場合や、`new_name` が `../other/x` や絶対パスのように
+/// 同じディレクトリの外を指す場合はエラーにする。`subfolders` が真なら `2024/01/x.jpg` のような
+/// 下位ディレクトリへの相対パスを許す。`root` は正規化済みであること。
+pub fn destination_within(
+    root: &Path,
+    path: &Path,
+    new_name: &str,
+    subfolders: bool,
+) -> Result<PathBuf, RenameError> {
+    let outside_root = || RenameError::OutsideRoot {
+        path: path.to_path_buf(),
+        root: root.to_path_buf(),
+    };
+    // シンボリックリンク自体をリネームするので、親ディレクトリだけを正規化する
+    let (Some(parent), Some(_)) = (path.parent(), path.file_name()) else {
+        return Err(outside_root());
+    };
+    let canonical = fs::canonicalize(parent).map_err(|e| RenameError::io(parent, &e))?;
+    if !canonical.starts_with(root) {
+        return Err(outside_root());
+    }
+
+    let components: Vec<Component> = Path::new(new_name).components().collect();
+    match components.as_slice() {
+        [Component::Normal(name)] if name.to_str() == Some(new_name) => {
+            Ok(path.with_file_name(new_name))
+        }
+        // 下位ディレクトリだけを指していれば、読み込んだディレクトリの外には出ない
+        [_, _, ..]
+            if subfolders
+                && !new_name.ends_with(std::path::is_separator)
+                && components
+                    .iter()
+                    .all(|component| matches!(component, Component::Normal(_))) =>
+        {
+            Ok(parent.join(components.iter().collect::<PathBuf>()))
+        }
+        _ => Err(RenameError::EscapesDirectory {
+            path: path.to_path_buf(),
+            new_name: new_name.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn kinds(name: &str, platform: TargetPlatform) -> Vec<ViolationKind> {
+        validate_name(name, platform)
+            .into_iter()
+            .map(|violation| violation.kind)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_ordinary_names() {
+        for name in [
+            "photo.jpg",
+            ".gitignore",
+            ".env",
+            ".config.json",
+            "日本語のファイル.txt",
+            "a b",
+        ] {
+            assert!(kinds(name, TargetPlatform::Portable).is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_names_on_every_platform() {
+        use ViolationKind::*;
+        for platform in [TargetPlatform::Linux, TargetPlatform::Windows] {
+            assert_eq!(kinds("", platform), [Empty]);
+            assert_eq!(kinds("  ", platform), [Empty]);
+            assert!(kinds("..", platform).contains(&ReservedName));
+            assert_eq!(kinds("a/b", platform), [PathSeparator]);
+            assert!(kinds("a\0b", platform).contains(&Nul));
+        }
+    }
+
+    #[test]
+    fn windows_rules_apply_only_to_windows_and_portable() {
+        use ViolationKind::*;
+        for (name, kind) in [
+            ("CON", ReservedName),
+            ("con.txt", ReservedName),
+            ("a?b", ForbiddenCharacter),
+            ("a:b", ForbiddenCharacter),
+            ("name.", TrailingDotOrSpace),
+            ("name ", TrailingDotOrSpace),
+            ("a\\b", PathSeparator),
+            ("a\tb", ControlCharacter),
+        ] {
+            assert!(kinds(name, TargetPlatform::Linux).is_empty(), "{}", name);
+            assert_eq!(kinds(name, TargetPlatform::Windows), [kind], "{}", name);
+            assert_eq!(kinds(name, TargetPlatform::Portable), [kind], "{}", name);
+        }
+    }
+
+    #[test]
+    fn length_is_counted_per_platform() {
+        // 3 バイトで UTF-16 では 1 単位の文字
+        let name = "あ".repeat(100);
+        assert_eq!(
+            kinds(&name, TargetPlatform::Linux),
+            [ViolationKind::TooLong]
+        );
+        assert!(kinds(&name, TargetPlatform::Windows).is_empty());
+        assert_eq!(
+            kinds(
+                &"a".repeat(MAX_NAME_UTF16_UNITS + 1),
+                TargetPlatform::Windows
+            ),
+            [ViolationKind::TooLong]
+        );
+    }
+
+    #[test]
+    fn relative_paths_are_checked_per_component() {
+        assert!(validate_relative_path("2024/01/x.jpg", TargetPlatform::Portable).is_empty());
+        assert!(validate_relative_path(".hidden/.env", TargetPlatform::Portable).is_empty());
+        assert!(!validate_relative_path("2024/", TargetPlatform::Portable).is_empty());
+        assert!(!validate_relative_path("2024/CON/x.jpg", TargetPlatform::Windows).is_empty());
+    }
+
+    #[test]
+    fn destination_stays_inside_the_folder() {
+        let dir = TempDir::new("destination-within");
+        let root = dir.root();
+        let path = dir.write("sub/a.txt", "");
+
+        assert_eq!(
+            destination_within(root, &path, "b.txt", false).unwrap(),
+            dir.path("sub/b.txt")
+        );
+        for new_name in ["../b.txt", "/tmp/b.txt", "x/b.txt", ".", ""] {
+            assert!(
+                matches!(
+                    destination_within(root, &path, new_name, false),
+                    Err(RenameError::EscapesDirectory { .. })
+                ),
+                "{}",
+                new_name
+            );
+        }
+    }
+
+    #[test]
+    fn destination_may_use_subfolders_when_allowed() {
+        let dir = TempDir::new("destination-subfolders");
+        let root = dir.root();
+        let path = dir.write("a.txt", "");
+
+        assert_eq!(
+            destination_within(root, &path, "2024/01/a.txt", true).unwrap(),
+            dir.path("2024/01/a.txt")
+        );
+        for new_name in ["2024/", "../a.txt", "2024/../../a.txt"] {
+            assert!(
+                destination_within(root, &path, new_name, true).is_err(),
+                "{}",
+                new_name
+            );
+        }
+    }
+
+    #[test]
+    fn destination_rejects_files_outside_the_root() {
+        let dir = TempDir::new("destination-root");
+        let other = TempDir::new("destination-other");
+        let path = other.write("a.txt", "");
+        assert!(matches!(
+            destination_within(dir.root(), &path, "b.txt", false),
+            Err(RenameError::OutsideRoot { .. })
+        ));
+    }
+}